@@ -1,5 +1,9 @@
-use reqwest::Client;
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::{Certificate, Client, Identity, RequestBuilder, StatusCode};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 /// ComputeHub 客户端（GridNode 使用）
 #[derive(Clone)]
@@ -19,12 +23,61 @@ pub struct TaskConfig {
     pub output_redis: Option<String>,
     pub input_queue: Option<String>,
     pub output_queue: Option<String>,
+    /// 本节点为该任务运行的容器数（None 表示按并行度全部运行）
+    #[serde(default)]
+    pub instances: Option<u32>,
+    /// 每个容器的资源需求（None 表示使用节点默认值）
+    #[serde(default)]
+    pub resources: Option<TaskResources>,
+    /// 额外的容器环境变量（内置变量优先）
+    #[serde(default)]
+    pub env: Option<HashMap<String, String>>,
+    /// 覆盖镜像的 CMD
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    /// 覆盖镜像的 ENTRYPOINT
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    /// 任务修订号（旧版 ComputeHub 不返回，视为 0）
+    #[serde(default)]
+    pub revision: u64,
+}
+
+/// 任务要求的每个容器资源（未设置的项使用节点默认值）
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct TaskResources {
+    #[serde(default)]
+    pub cpus: Option<f64>,
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    #[serde(default)]
+    pub pids_limit: Option<i64>,
+    #[serde(default)]
+    pub shm_size_mb: Option<u64>,
+}
+
+impl TaskConfig {
+    /// 任务标识：任务名 + 修订号，任意一个变化都需要重启容器
+    pub fn revision_key(&self) -> (String, u64) {
+        (self.task_name.clone(), self.revision)
+    }
+
+    /// 指定实例是否参与该任务（实例 ID 超出分配的容器数时保持空闲）
+    pub fn runs_on_instance(&self, instance_id: u32) -> bool {
+        self.instances.is_none_or(|n| instance_id < n)
+    }
 }
 
 #[derive(Debug, Deserialize)]
 pub struct RegisterResponse {
     pub node_id: String,
     pub current_task: Option<TaskConfig>,
+    /// 新签发的节点专属凭据（旧版 ComputeHub 不返回）
+    #[serde(default)]
+    pub node_secret: Option<String>,
+    /// 管理员设置的并行容器数（None 表示使用本地配置）
+    #[serde(default)]
+    pub parallelism: Option<u32>,
 }
 
 #[derive(Debug, Serialize)]
@@ -33,6 +86,9 @@ pub struct RegisterRequest {
     pub hostname: String,
     pub architecture: String,
     pub cpu_count: u32,
+    pub labels: HashMap<String, String>,
+    /// 申请节点专属凭据
+    pub request_credential: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -40,11 +96,84 @@ pub struct HeartbeatRequest {
     pub node_id: String,
     pub status: NodeRuntimeStatus,
     pub active_containers: u32,
+    /// 各工作实例的容器状态
+    pub instances: Vec<InstanceStatus>,
+}
+
+/// 工作实例的容器状态（随心跳上报）
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct InstanceStatus {
+    pub instance_id: u32,
+    pub state: InstanceState,
+    /// 当前任务名
+    pub task: Option<String>,
+    /// 当前任务的镜像
+    pub image: Option<String>,
+    /// 运行中的容器 ID
+    pub container_id: Option<String>,
+    /// 上一个容器的退出码
+    pub last_exit_code: Option<i64>,
+    /// 最近的错误（镜像拉取失败、容器启动失败或非零退出）
+    pub error: Option<String>,
+    /// 当前任务（修订）的容器重启次数：启动失败后重试的次数，0 表示首次启动即成功
+    /// 任务或修订变化时清零；容器退出后不会自动重新启动
+    pub restart_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub enum InstanceState {
+    /// 没有分配任务
+    #[default]
+    Idle,
+    /// 正在拉取镜像
+    Pulling,
+    /// 容器运行中
+    Running,
+    /// 容器已退出
+    Exited,
+    /// 镜像拉取或容器启动失败
+    Failed,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct HeartbeatResponse {
     pub stop_requested: bool,
+    /// 管理员设置的节点模式（旧版 ComputeHub 不返回，按 stop_requested 判断）
+    #[serde(default)]
+    pub mode: Option<NodeMode>,
+    /// 管理员设置的并行容器数（None 表示使用本地配置）
+    #[serde(default)]
+    pub parallelism: Option<u32>,
+}
+
+impl HeartbeatResponse {
+    pub fn mode(&self) -> NodeMode {
+        match self.mode {
+            Some(mode) => mode,
+            None if self.stop_requested => NodeMode::Stopping,
+            None => NodeMode::Active,
+        }
+    }
+}
+
+/// 节点模式（由 ComputeHub 管理员设置）
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum NodeMode {
+    /// 正常运行
+    Active,
+    /// 排空：不启动新容器，运行中的容器自然结束
+    Draining,
+    /// 停止所有容器后退出
+    Stopping,
+}
+
+/// 连接 ComputeHub 使用的 TLS 文件（PEM）
+#[derive(Debug, Clone, Default)]
+pub struct ClientTls {
+    /// 只信任该 CA 签发的服务端证书（用于自签名 CA），None 时使用系统根证书
+    pub ca_file: Option<PathBuf>,
+    /// 客户端证书和私钥（双向 TLS，代替节点密钥认证）
+    pub identity: Option<(PathBuf, PathBuf)>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize)]
@@ -55,12 +184,53 @@ pub enum NodeRuntimeStatus {
 }
 
 impl ComputeHubClient {
-    pub fn new(base_url: String, token: String, platform: String) -> Self {
-        Self {
-            client: Client::new(),
+    /// token 为空时不发送 Authorization 头（仅使用客户端证书认证）
+    pub fn new(
+        base_url: String,
+        token: String,
+        platform: String,
+        tls: &ClientTls,
+    ) -> anyhow::Result<Self> {
+        let mut builder = Client::builder();
+        if let Some(ca_file) = &tls.ca_file {
+            let pem = read_pem(ca_file)?;
+            let certs = Certificate::from_pem_bundle(&pem)?;
+            if certs.is_empty() {
+                anyhow::bail!("No certificates found in CA file {}", ca_file.display());
+            }
+            builder = builder.tls_built_in_root_certs(false);
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if let Some((cert_file, key_file)) = &tls.identity {
+            let mut pem = read_pem(cert_file)?;
+            pem.push(b'\n');
+            pem.extend(read_pem(key_file)?);
+            builder = builder.identity(Identity::from_pem(&pem)?);
+        }
+        Ok(Self {
+            client: builder.build()?,
             base_url,
             token,
             platform,
+        })
+    }
+
+    /// 添加 Authorization 头（没有 token 时依赖客户端证书）
+    fn authorized(&self, request: RequestBuilder) -> RequestBuilder {
+        if self.token.is_empty() {
+            request
+        } else {
+            request.bearer_auth(&self.token)
+        }
+    }
+
+    /// 使用另一个凭据（如注册后签发的节点专属凭据）的客户端
+    pub fn with_token(&self, token: String) -> Self {
+        Self {
+            token,
+            ..self.clone()
         }
     }
 
@@ -71,6 +241,8 @@ impl ComputeHubClient {
         hostname: String,
         architecture: String,
         cpu_count: u32,
+        labels: HashMap<String, String>,
+        request_credential: bool,
     ) -> anyhow::Result<RegisterResponse> {
         let url = format!("{}/gridnode/register", self.base_url);
         let req = RegisterRequest {
@@ -78,12 +250,12 @@ impl ComputeHubClient {
             hostname,
             architecture,
             cpu_count,
+            labels,
+            request_credential,
         };
 
         let resp = self
-            .client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
+            .authorized(self.client.post(&url))
             .json(&req)
             .send()
             .await?;
@@ -91,6 +263,8 @@ impl ComputeHubClient {
         if resp.status().is_success() {
             let data: RegisterResponse = resp.json().await?;
             Ok(data)
+        } else if resp.status() == StatusCode::FORBIDDEN {
+            Err(NodeRejected(resp.text().await.unwrap_or_default()).into())
         } else {
             let status = resp.status();
             let text = resp.text().await.unwrap_or_default();
@@ -103,44 +277,46 @@ impl ComputeHubClient {
     }
 
     /// 发送心跳
-    /// 返回: (成功, 是否请求停止)
+    /// 返回: ComputeHub 的响应（None 表示 ComputeHub 不认识该节点）；节点被拒绝时返回 NodeRejected 错误
     pub async fn heartbeat(
         &self,
         node_id: &str,
         status: NodeRuntimeStatus,
         active_containers: u32,
-    ) -> anyhow::Result<(bool, bool)> {
+        instances: Vec<InstanceStatus>,
+    ) -> anyhow::Result<Option<HeartbeatResponse>> {
         let url = format!("{}/gridnode/heartbeat", self.base_url);
         let req = HeartbeatRequest {
             node_id: node_id.to_string(),
             status,
             active_containers,
+            instances,
         };
 
         let resp = self
-            .client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
+            .authorized(self.client.post(&url))
             .json(&req)
             .send()
             .await?;
 
         if resp.status().is_success() {
-            let data: HeartbeatResponse = resp.json().await?;
-            Ok((true, data.stop_requested))
+            Ok(Some(resp.json().await?))
+        } else if resp.status() == StatusCode::FORBIDDEN {
+            Err(NodeRejected(resp.text().await.unwrap_or_default()).into())
         } else {
-            Ok((false, false))
+            Ok(None)
         }
     }
 
-    /// 获取当前任务
-    pub async fn get_task(&self) -> anyhow::Result<Option<TaskConfig>> {
-        let url = format!("{}/gridnode/task?platform={}", self.base_url, self.platform);
+    /// 获取分配给本节点的任务
+    pub async fn get_task(&self, node_id: &str) -> anyhow::Result<Option<TaskConfig>> {
+        let url = format!(
+            "{}/gridnode/task?platform={}&node_id={}",
+            self.base_url, self.platform, node_id
+        );
 
         let resp = self
-            .client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", self.token))
+            .authorized(self.client.get(&url))
             .send()
             .await?;
 
@@ -154,4 +330,67 @@ impl ComputeHubClient {
             ))
         }
     }
+
+    /// 长轮询分配给本节点的任务：etag 与 ComputeHub 当前配置相同时，最多等待 wait_secs 秒
+    /// 返回 None 表示等待期间配置没有变化
+    pub async fn watch_task(
+        &self,
+        node_id: &str,
+        etag: Option<&str>,
+        wait_secs: u64,
+    ) -> anyhow::Result<Option<TaskUpdate>> {
+        let url = format!(
+            "{}/gridnode/task?platform={}&node_id={}&wait={}",
+            self.base_url, self.platform, node_id, wait_secs
+        );
+
+        let mut request = self
+            .authorized(self.client.get(&url))
+            // 给 ComputeHub 留出返回 304 的时间
+            .timeout(Duration::from_secs(wait_secs + 10));
+        if let Some(etag) = etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        let resp = request.send().await?;
+
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(anyhow::anyhow!("Failed to watch task: {}", resp.status()));
+        }
+        let etag = resp
+            .headers()
+            .get(ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let task: Option<TaskConfig> = resp.json().await?;
+        Ok(Some(TaskUpdate { task, etag }))
+    }
+}
+
+/// 长轮询返回的任务配置
+/// ComputeHub 拒绝该节点（403：节点已被吊销，或不允许用共享密钥注册新节点）
+/// 收到后节点应停止所有容器并退出，而不是继续重试
+#[derive(Debug)]
+pub struct NodeRejected(pub String);
+
+impl std::fmt::Display for NodeRejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Node rejected by ComputeHub (403): {}", self.0)
+    }
+}
+
+impl std::error::Error for NodeRejected {}
+
+#[derive(Debug)]
+pub struct TaskUpdate {
+    pub task: Option<TaskConfig>,
+    /// 配置的 ETag（旧版 ComputeHub 不支持长轮询，立即返回且没有 ETag）
+    pub etag: Option<String>,
+}
+
+/// 读取 PEM 文件
+fn read_pem(path: &Path) -> anyhow::Result<Vec<u8>> {
+    std::fs::read(path).map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))
 }
@@ -1,16 +1,39 @@
+use crate::client::{ClientTls, TaskConfig, TaskResources};
+use crate::docker::ContainerResources;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+/// 容器 CPU 限制的下限（Docker 不接受低于 0.01 核的 NanoCpus）
+const MIN_CONTAINER_CPUS: f64 = 0.01;
 
 /// GridNode 配置文件
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct GridNodeConfig {
     /// ComputeHub 服务端地址
     pub server_url: String,
-    /// 节点认证 Token
+    /// 节点认证 Token（只使用客户端证书认证时可以省略）
+    #[serde(default)]
     pub token: String,
+    /// ComputeHub 的 CA 证书文件（PEM），设置后只信任该 CA 签发的服务端证书
+    #[serde(default)]
+    pub ca_file: Option<String>,
+    /// 客户端证书（PEM），ComputeHub 配置了 tls_client_ca 时用于代替 token 认证
+    /// 证书主题的 CN 即节点 ID
+    #[serde(default)]
+    pub client_cert: Option<String>,
+    /// 客户端证书的私钥（PEM）
+    #[serde(default)]
+    pub client_key: Option<String>,
     /// 节点唯一 ID（首次运行时生成，保存到文件）
     pub node_id: Option<String>,
-    /// 并行容器数（默认使用 CPU 核心数）
+    /// 节点专属凭据（首次注册时由 ComputeHub 签发，保存到文件）
+    /// 存在时代替 token 访问 ComputeHub
+    #[serde(default)]
+    pub node_secret: Option<String>,
+    /// 并行容器数（默认使用 CPU 核心数，至少为 1）
     #[serde(default)]
     pub parallelism: Option<u32>,
     /// 心跳间隔（秒）
@@ -23,6 +46,25 @@ pub struct GridNodeConfig {
     /// 每个容器的内存限制（MB）
     #[serde(default = "default_container_memory")]
     pub container_memory: u64,
+    /// 单个容器最多使用的 CPU 核数（默认为并行度，不超过本机实际核数）
+    #[serde(default)]
+    pub max_container_cpus: Option<f64>,
+    /// 单个容器最大内存（MB，默认不限制）
+    #[serde(default)]
+    pub max_container_memory: Option<u64>,
+    /// 所有容器的内存总和上限（MB，默认不限制）
+    /// 任务要求的内存较大时，据此减少同时运行的容器数
+    #[serde(default)]
+    pub memory_budget: Option<u64>,
+    /// 单个容器最大进程数（默认不限制）
+    #[serde(default)]
+    pub max_container_pids: Option<i64>,
+    /// 单个容器 /dev/shm 最大值（MB，默认不限制）
+    #[serde(default)]
+    pub max_container_shm: Option<u64>,
+    /// 节点标签（注册时发送给 ComputeHub，用于匹配任务的 node_selector）
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
 }
 
 fn default_heartbeat_interval() -> u64 {
@@ -42,11 +84,21 @@ impl Default for GridNodeConfig {
         Self {
             server_url: "http://localhost:8080".to_string(),
             token: "default-token".to_string(),
+            ca_file: None,
+            client_cert: None,
+            client_key: None,
             node_id: None,
+            node_secret: None,
             parallelism: None,
             heartbeat_interval: 30,
             stop_timeout: 30,      // 默认30秒
             container_memory: 1024, // 默认1024MB (1GB)
+            max_container_cpus: None,
+            max_container_memory: None,
+            memory_budget: None,
+            max_container_pids: None,
+            max_container_shm: None,
+            labels: HashMap::new(),
         }
     }
 }
@@ -68,16 +120,34 @@ impl GridNodeConfig {
     pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let content = std::fs::read_to_string(path)?;
         let config: GridNodeConfig = toml::from_str(&content)?;
+        if config.parallelism == Some(0) {
+            anyhow::bail!("parallelism must be at least 1");
+        }
         Ok(config)
     }
 
     /// 保存配置到文件
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
         let content = toml::to_string_pretty(self)?;
-        std::fs::write(path, content)?;
+        std::fs::write(&path, content)?;
+        // 配置中包含 token 和节点专属凭据，只允许所有者读写
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
         Ok(())
     }
 
+    /// 连接 ComputeHub 使用的 TLS 文件（客户端证书和私钥必须同时设置）
+    pub fn client_tls(&self) -> anyhow::Result<ClientTls> {
+        let identity = match (&self.client_cert, &self.client_key) {
+            (Some(cert), Some(key)) => Some((PathBuf::from(cert), PathBuf::from(key))),
+            (None, None) => None,
+            _ => anyhow::bail!("client_cert and client_key must be set together"),
+        };
+        Ok(ClientTls {
+            ca_file: self.ca_file.as_ref().map(PathBuf::from),
+            identity,
+        })
+    }
+
     /// 获取并行度（默认 CPU 核心数）
     pub fn get_parallelism(&self) -> u32 {
         self.parallelism.unwrap_or_else(|| {
@@ -86,6 +156,84 @@ impl GridNodeConfig {
                 .unwrap_or(1)
         })
     }
+
+    /// 计算任务容器的实际资源：未设置的项使用默认值（1 核、container_memory），超出节点上限的裁剪到上限
+    /// CPU 不超过本机实际核数（Docker 拒绝超过核数的 NanoCpus），且不低于 Docker 接受的下限 0.01
+    pub fn container_resources(&self, requested: Option<&TaskResources>) -> ContainerResources {
+        let requested = requested.cloned().unwrap_or_default();
+        let host_cpus = std::thread::available_parallelism()
+            .map(|n| n.get() as f64)
+            .unwrap_or(1.0);
+        let max_cpus = self
+            .max_container_cpus
+            .unwrap_or(self.get_parallelism() as f64)
+            .min(host_cpus);
+
+        let cpus = requested
+            .cpus
+            .unwrap_or(1.0)
+            .min(max_cpus)
+            .max(MIN_CONTAINER_CPUS);
+        let memory_mb = clamp_option(
+            requested.memory_mb.unwrap_or(self.container_memory),
+            self.max_container_memory,
+        );
+        let pids_limit = requested
+            .pids_limit
+            .map(|pids| clamp_option(pids, self.max_container_pids))
+            .or(self.max_container_pids);
+        let shm_size_mb = requested
+            .shm_size_mb
+            .map(|shm| clamp_option(shm, self.max_container_shm));
+
+        ContainerResources {
+            cpus,
+            memory_mb,
+            pids_limit,
+            shm_size_mb,
+        }
+    }
+
+    /// 按容器资源计算本节点最多能同时运行的容器数（不超过并行度，至少 1）
+    pub fn instance_capacity(&self, resources: &ContainerResources) -> u32 {
+        let parallelism = self.get_parallelism();
+        let by_cpu = (parallelism as f64 / resources.cpus).floor() as u32;
+        let by_memory = self
+            .memory_budget
+            .map_or(u32::MAX, |budget| (budget / resources.memory_mb.max(1)) as u32);
+        by_cpu.min(by_memory).clamp(1, parallelism.max(1))
+    }
+
+    /// 按本节点上限调整任务配置：资源超出上限时记录警告，并根据资源需求减少运行的容器数
+    pub fn limit_task(&self, mut task: TaskConfig) -> TaskConfig {
+        let resources = self.container_resources(task.resources.as_ref());
+        if let Some(requested) = &task.resources {
+            let cpus_clamped = requested.cpus.is_some_and(|cpus| cpus > resources.cpus);
+            let memory_clamped = requested
+                .memory_mb
+                .is_some_and(|memory| memory > resources.memory_mb);
+            if cpus_clamped || memory_clamped {
+                warn!(
+                    "Task '{}' requests more resources than this node allows, clamped to {} CPUs / {} MB",
+                    task.task_name, resources.cpus, resources.memory_mb
+                );
+            }
+        }
+
+        let capacity = self.instance_capacity(&resources);
+        if capacity < self.get_parallelism() {
+            task.instances = Some(task.instances.map_or(capacity, |n| n.min(capacity)));
+        }
+        task
+    }
+}
+
+/// 有上限时裁剪到上限
+fn clamp_option<T: Ord>(value: T, max: Option<T>) -> T {
+    match max {
+        Some(max) => value.min(max),
+        None => value,
+    }
 }
 
 /// 生成默认配置文件内容
@@ -98,10 +246,23 @@ server_url = "http://localhost:8080"
 # 节点认证 Token（与服务端配置匹配）
 token = "your-secret-token"
 
+# ComputeHub 使用自签名证书（https://）时，指定签发该证书的 CA 文件（PEM）
+# 设置后只信任该 CA，不再使用系统根证书
+# ca_file = "/etc/idm-gridcore/tls/ca.pem"
+
+# 双向 TLS：ComputeHub 配置了 tls_client_ca 时，可以用客户端证书代替 token 认证
+# 节点 ID 取自证书主题的 CN
+# client_cert = "/etc/idm-gridcore/tls/node.pem"
+# client_key = "/etc/idm-gridcore/tls/node-key.pem"
+
 # 节点唯一 ID（首次启动由 ComputeHub 分配，自动保存）
 # node_id = ""
 
-# 并行容器数（默认使用 CPU 核心数）
+# 节点专属凭据（首次注册时由 ComputeHub 签发，自动保存）
+# 保存后 token 只在专属凭据失效时用于重新注册，可以从本文件删除
+# node_secret = ""
+
+# 并行容器数（默认使用 CPU 核心数，至少为 1）
 # parallelism = 4
 
 # 心跳间隔（秒）
@@ -113,7 +274,24 @@ heartbeat_interval = 30
 # 如果你的计算容器需要处理信号并完成当前工作，请设置足够长的时间
 # stop_timeout = 30
 
-# 每个容器的内存限制（MB）
+# 每个容器的内存限制（MB，任务未指定 resources.memory_mb 时使用）
 # container_memory = 1024
+
+# 任务可以通过 resources 指定每个容器的 CPU/内存/进程数/shm，以下上限用于裁剪
+# 单个容器最多使用的 CPU 核数（默认为并行度）
+# max_container_cpus = 4
+# 单个容器最大内存（MB）
+# max_container_memory = 8192
+# 所有容器的内存总和上限（MB），任务要求的内存较大时自动减少同时运行的容器数
+# memory_budget = 16384
+# 单个容器最大进程数
+# max_container_pids = 4096
+# 单个容器 /dev/shm 最大值（MB）
+# max_container_shm = 2048
+
+# 节点标签（任务可以通过 node_selector 只在匹配的节点上运行）
+# [labels]
+# location = "datacenter"
+# gpu = "true"
 "#.to_string()
 }
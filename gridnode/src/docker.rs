@@ -4,6 +4,32 @@ use bollard::models::HostConfig;
 use std::collections::HashMap;
 use tracing::{info, error, warn};
 
+/// 容器实际使用的资源（已按节点上限裁剪）
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerResources {
+    /// CPU 核数
+    pub cpus: f64,
+    /// 内存限制（MB）
+    pub memory_mb: u64,
+    /// 最大进程数（None 表示不限制）
+    pub pids_limit: Option<i64>,
+    /// /dev/shm 大小（MB，None 表示 Docker 默认值）
+    pub shm_size_mb: Option<u64>,
+}
+
+/// 任务相关的容器启动参数
+#[derive(Debug, Clone)]
+pub struct ContainerSpec {
+    /// 资源限制
+    pub resources: ContainerResources,
+    /// 额外的环境变量（与内置变量同名时内置变量优先）
+    pub env: HashMap<String, String>,
+    /// 覆盖镜像的 CMD
+    pub command: Option<Vec<String>>,
+    /// 覆盖镜像的 ENTRYPOINT
+    pub entrypoint: Option<Vec<String>>,
+}
+
 /// Docker 管理器
 pub struct DockerManager {
     docker: Docker,
@@ -35,7 +61,7 @@ impl DockerManager {
         match Docker::connect_with_local_defaults() {
             Ok(docker) => {
                 info!("Connected to Docker");
-                return Ok(docker);
+                Ok(docker)
             }
             Err(e) => {
                 let err_msg = e.to_string();
@@ -92,7 +118,7 @@ impl DockerManager {
                     }
                 }
                 
-                return Err(Self::connection_error(&e.to_string()));
+                Err(Self::connection_error(&e.to_string()))
             }
         }
     }
@@ -163,19 +189,19 @@ impl DockerManager {
         node_id: &str,
         instance_id: usize,
         env_vars: HashMap<String, String>,
-        memory_mb: u64,
+        spec: &ContainerSpec,
     ) -> anyhow::Result<String> {
         let container_name = format!("idm-{}-{}-{}", task_name, node_id, instance_id);
         
         // 尝试创建容器
-        match self.try_create_container(&container_name, image, &env_vars, memory_mb).await {
+        match self.try_create_container(&container_name, image, &env_vars, spec).await {
             Ok(container) => Ok(container),
             Err(e) => {
                 // 如果容器已存在，删除后重试
                 if e.to_string().contains("Conflict") {
                     warn!("Container {} already exists, removing and recreating", container_name);
                     let _ = self.docker.remove_container(&container_name, None).await;
-                    self.try_create_container(&container_name, image, &env_vars, memory_mb).await
+                    self.try_create_container(&container_name, image, &env_vars, spec).await
                 } else {
                     Err(e)
                 }
@@ -188,10 +214,14 @@ impl DockerManager {
         container_name: &str,
         image: &str,
         env_vars: &HashMap<String, String>,
-        memory_mb: u64,
+        spec: &ContainerSpec,
     ) -> anyhow::Result<String> {
-        // 准备环境变量
-        let env: Vec<String> = env_vars
+        let resources = &spec.resources;
+
+        // 准备环境变量：任务的额外变量在前，内置变量覆盖同名项
+        let mut merged = spec.env.clone();
+        merged.extend(env_vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let env: Vec<String> = merged
             .iter()
             .map(|(k, v)| format!("{}={}", k, v))
             .collect();
@@ -199,10 +229,14 @@ impl DockerManager {
         let config = Config {
             image: Some(image.to_string()),
             env: Some(env),
+            cmd: spec.command.clone(),
+            entrypoint: spec.entrypoint.clone(),
             host_config: Some(HostConfig {
                 // 限制资源
-                cpu_count: Some(1i64),
-                memory: Some(memory_mb as i64 * 1024 * 1024), // MB 转 bytes
+                nano_cpus: Some((resources.cpus * 1e9) as i64),
+                memory: Some(mb_to_bytes(resources.memory_mb)),
+                pids_limit: resources.pids_limit,
+                shm_size: resources.shm_size_mb.map(mb_to_bytes),
                 ..Default::default()
             }),
             ..Default::default()
@@ -236,25 +270,22 @@ impl DockerManager {
         
         use futures::StreamExt;
         
-        while let Some(result) = stream.next().await {
-            match result {
-                Ok(response) => {
-                    let exit_code = response.status_code;
-                    if exit_code == 0 {
-                        info!("Container {} exited successfully", container_id);
-                    } else {
-                        warn!("Container {} exited with code {}", container_id, exit_code);
-                    }
-                    return Ok(exit_code);
-                }
-                Err(e) => {
-                    error!("Error waiting for container {}: {}", container_id, e);
-                    return Err(e.into());
+        match stream.next().await {
+            Some(Ok(response)) => {
+                let exit_code = response.status_code;
+                if exit_code == 0 {
+                    info!("Container {} exited successfully", container_id);
+                } else {
+                    warn!("Container {} exited with code {}", container_id, exit_code);
                 }
+                Ok(exit_code)
+            }
+            Some(Err(e)) => {
+                error!("Error waiting for container {}: {}", container_id, e);
+                Err(e.into())
             }
+            None => Err(anyhow::anyhow!("Wait stream ended unexpectedly")),
         }
-
-        Err(anyhow::anyhow!("Wait stream ended unexpectedly"))
     }
 
     /// 拉取镜像（根据当前架构）
@@ -286,11 +317,11 @@ impl DockerManager {
                 Ok(_) => {}
                 Err(e) => {
                     let msg = e.to_string();
-                    // 镜像已存在不是错误
-                    if msg.contains("not found") || msg.contains("pull access denied") {
-                        has_error = true;
-                        error_msg = msg;
-                    } else if msg.contains("permission denied") {
+                    // 镜像不存在、无拉取权限或权限不足属于致命错误
+                    if msg.contains("not found")
+                        || msg.contains("pull access denied")
+                        || msg.contains("permission denied")
+                    {
                         has_error = true;
                         error_msg = msg;
                     } else {
@@ -387,14 +418,11 @@ impl DockerManager {
         for container in containers {
             if let Some(names) = container.names {
                 for name in names {
-                    if name.starts_with("/idm-") {
-                        if let Some(state) = &container.state {
-                            if state == "exited" {
-                                if let Some(id) = &container.id {
-                                    let _ = self.docker.remove_container(id, None).await;
-                                }
-                            }
-                        }
+                    if name.starts_with("/idm-")
+                        && container.state.as_deref() == Some("exited")
+                        && let Some(id) = &container.id
+                    {
+                        let _ = self.docker.remove_container(id, None).await;
                     }
                 }
             }
@@ -403,3 +431,10 @@ impl DockerManager {
         Ok(())
     }
 }
+
+/// MB 转 bytes（超出 i64 范围时取 i64::MAX，不会溢出成负数）
+fn mb_to_bytes(mb: u64) -> i64 {
+    i64::try_from(mb)
+        .unwrap_or(i64::MAX)
+        .saturating_mul(1024 * 1024)
+}
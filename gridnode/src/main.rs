@@ -5,20 +5,28 @@ mod client;
 mod config;
 mod docker;
 
-use crate::client::{ComputeHubClient, NodeRuntimeStatus, TaskConfig};
+use crate::client::{
+    ComputeHubClient, InstanceState, InstanceStatus, NodeMode, NodeRejected, NodeRuntimeStatus,
+    TaskConfig, TaskUpdate,
+};
 use crate::config::GridNodeConfig;
-use crate::docker::DockerManager;
+use crate::docker::{ContainerSpec, DockerManager};
 use std::collections::HashMap;
 use std::path::PathBuf;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use std::sync::Arc;
 use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::{Mutex, watch};
+use tokio::task::JoinHandle;
 use tokio::time::{interval, sleep, Duration};
 use tracing::{error, info, warn};
 
 const CONFIG_FILENAME: &str = "gridnode.toml";
 const CONFIG_DIR_NAME: &str = "idm-gridcore";
+/// 长轮询任务变化时每次请求的等待时间（秒）
+const TASK_WATCH_WAIT_SECS: u64 = 30;
+/// 不支持长轮询时的轮询间隔（秒）
+const TASK_POLL_INTERVAL_SECS: u64 = 10;
 
 /// 获取配置文件路径（按优先级）
 /// 1. 环境变量 IDM_GRIDCORE_CONFIG
@@ -37,12 +45,10 @@ fn get_config_path() -> PathBuf {
     }
     
     // 优先级3：用户配置目录
-    let home_config = dirs::config_dir()
+    dirs::config_dir()
         .unwrap_or_else(|| PathBuf::from("."))
         .join(CONFIG_DIR_NAME)
-        .join(CONFIG_FILENAME);
-    
-    home_config
+        .join(CONFIG_FILENAME)
 }
 
 /// 获取配置目录路径（用于创建默认配置）
@@ -97,12 +103,18 @@ async fn main() -> anyhow::Result<()> {
     };
     info!("Detected platform: {} (architecture: {})", platform, architecture);
 
-    // 创建客户端
-    let client = ComputeHubClient::new(
+    // 创建客户端（共享的节点密钥，用于首次注册）
+    let bootstrap_client = ComputeHubClient::new(
         config.server_url.clone(),
         config.token.clone(),
         platform.to_string(),
-    );
+        &config.client_tls()?,
+    )?;
+    // 已有专属凭据时使用专属凭据
+    let mut client = match &config.node_secret {
+        Some(secret) => bootstrap_client.with_token(secret.clone()),
+        None => bootstrap_client.clone(),
+    };
 
     // 注册节点（不传 node_id，让 ComputeHub 分配）
     let parallelism = config.get_parallelism();
@@ -114,23 +126,57 @@ async fn main() -> anyhow::Result<()> {
     let hostname = config.get_hostname();
     let architecture = config.get_architecture();
     
-    let register_resp = match client
+    let mut register_result = client
         .register(
             existing_node_id.clone(), // 首次为 None，后续为已有 ID
-            hostname,
-            architecture,
+            hostname.clone(),
+            architecture.clone(),
             parallelism,
+            config.labels.clone(),
+            // 没有专属凭据时申请（使用客户端证书时不需要）
+            config.node_secret.is_none() && config.client_cert.is_none(),
         )
-        .await
+        .await;
+
+    // 专属凭据被拒绝（如 ComputeHub 状态丢失），用共享密钥重新注册并申请新凭据
+    // 节点被吊销（403）时不重试
+    if let Err(e) = &register_result
+        && config.node_secret.is_some()
+        && !e.is::<NodeRejected>()
     {
+        warn!("Registration with node credential failed ({}), retrying with bootstrap token", e);
+        client = bootstrap_client.clone();
+        register_result = client
+            .register(
+                existing_node_id.clone(),
+                hostname,
+                architecture,
+                parallelism,
+                config.labels.clone(),
+                true,
+            )
+            .await;
+    }
+
+    let register_resp = match register_result {
         Ok(resp) => {
             info!("Registered successfully with node_id: {}", resp.node_id);
 
-            // 如果是首次注册（配置文件没有 node_id），保存到配置文件
+            // 如果是首次注册（配置文件没有 node_id）或签发了新凭据，保存到配置文件
+            let mut config_changed = false;
             if existing_node_id.is_none() {
                 config.node_id = Some(resp.node_id.clone());
+                config_changed = true;
+            }
+            if let Some(secret) = &resp.node_secret {
+                config.node_secret = Some(secret.clone());
+                client = bootstrap_client.with_token(secret.clone());
+                config_changed = true;
+                info!("Received node credential from ComputeHub");
+            }
+            if config_changed {
                 config.save_to_file(&config_path)?;
-                info!("Saved node_id to config file");
+                info!("Saved node_id / node credential to config file");
             }
 
             resp
@@ -157,14 +203,25 @@ async fn main() -> anyhow::Result<()> {
     let heartbeat_node_id = node_id.clone();
     let heartbeat_interval = config.heartbeat_interval;
     let active_containers = Arc::new(Mutex::new(0u32));
-    let container_errors = Arc::new(Mutex::new(HashMap::<u32, String>::new()));
+    let instances: InstanceTable = Arc::new(Mutex::new(HashMap::new()));
     let active_containers_for_heartbeat = active_containers.clone();
-    let container_errors_for_heartbeat = container_errors.clone();
+    let instances_for_heartbeat = instances.clone();
     
     // 停止信号（用于优雅退出）
     let stop_requested = Arc::new(AtomicBool::new(false));
     let stop_requested_for_heartbeat = stop_requested.clone();
     let stop_requested_for_signal = stop_requested.clone();
+    // ComputeHub 拒绝该节点（已被吊销）：停止所有容器后以错误退出
+    let rejected = Arc::new(AtomicBool::new(false));
+    let rejected_for_heartbeat = rejected.clone();
+    // 排空模式（管理员设置，可随时取消）：不启动新容器，运行中的容器自然结束
+    let draining = Arc::new(AtomicBool::new(false));
+    let draining_for_heartbeat = draining.clone();
+    // 期望的工作实例数（默认本地配置的并行度，可由 ComputeHub 调低）
+    let desired_parallelism = Arc::new(AtomicU32::new(
+        register_resp.parallelism.unwrap_or(parallelism).clamp(1, parallelism.max(1)),
+    ));
+    let desired_parallelism_for_heartbeat = desired_parallelism.clone();
 
     // 监听系统信号（SIGINT, SIGTERM）用于本地优雅退出
     tokio::spawn(async move {
@@ -200,10 +257,12 @@ async fn main() -> anyhow::Result<()> {
         loop {
             interval.tick().await;
             let count = *active_containers_for_heartbeat.lock().await;
-            let errors = container_errors_for_heartbeat.lock().await;
+            let mut instance_statuses: Vec<InstanceStatus> =
+                instances_for_heartbeat.lock().await.values().cloned().collect();
+            instance_statuses.sort_by_key(|instance| instance.instance_id);
             
-            // 如果有错误，状态设为 Error
-            let status = if !errors.is_empty() {
+            // 如果有实例出错，状态设为 Error
+            let status = if instance_statuses.iter().any(|instance| instance.error.is_some()) {
                 NodeRuntimeStatus::Error
             } else if count > 0 {
                 NodeRuntimeStatus::Running
@@ -212,19 +271,39 @@ async fn main() -> anyhow::Result<()> {
             };
 
             match heartbeat_client
-                .heartbeat(&heartbeat_node_id, status, count)
+                .heartbeat(&heartbeat_node_id, status, count, instance_statuses)
                 .await
             {
-                Ok((true, stop_requested_from_server)) => {
-                    if stop_requested_from_server {
+                Ok(Some(resp)) => {
+                    let mode = resp.mode();
+                    if mode == NodeMode::Stopping {
                         info!("Stop requested by ComputeHub, initiating graceful shutdown...");
                         stop_requested_for_heartbeat.store(true, Ordering::SeqCst);
                         break;
                     }
+                    let drain = mode == NodeMode::Draining;
+                    if draining_for_heartbeat.swap(drain, Ordering::SeqCst) != drain {
+                        if drain {
+                            info!("Drain requested by ComputeHub, no new containers will be started");
+                        } else {
+                            info!("Drain cancelled by ComputeHub, resuming");
+                        }
+                    }
+                    // 不超过本地配置的并行度
+                    let desired = resp.parallelism.unwrap_or(parallelism).clamp(1, parallelism.max(1));
+                    if desired_parallelism_for_heartbeat.swap(desired, Ordering::SeqCst) != desired {
+                        info!("Parallelism set to {} by ComputeHub", desired);
+                    }
                 }
-                Ok((false, _)) => {
+                Ok(None) => {
                     warn!("Heartbeat returned false, node may not be recognized");
                 }
+                Err(e) if e.is::<NodeRejected>() => {
+                    error!("{}; stopping all containers and exiting", e);
+                    rejected_for_heartbeat.store(true, Ordering::SeqCst);
+                    stop_requested_for_heartbeat.store(true, Ordering::SeqCst);
+                    break;
+                }
                 Err(e) => {
                     warn!("Heartbeat failed: {}", e);
                 }
@@ -233,250 +312,101 @@ async fn main() -> anyhow::Result<()> {
     });
 
     // 使用 watch channel 来通知任务变化
-    let (task_tx, _task_rx) = watch::channel(register_resp.current_task.clone());
+    // 下发的任务配置先按本节点资源上限调整（可能减少运行的容器数）
+    let (task_tx, _task_rx) = watch::channel(
+        register_resp
+            .current_task
+            .clone()
+            .map(|task| config.limit_task(task)),
+    );
     let task_tx = Arc::new(Mutex::new(task_tx));
     let task_tx_for_watcher = task_tx.clone();
 
-    // 启动任务监控线程（轮询 ComputeHub 获取最新任务）
+    // 启动任务监控线程
+    // 优先长轮询（任务变化立即返回）；ComputeHub 不支持或长轮询失败时，退回每 10 秒轮询一次
     let task_watcher_client = client.clone();
+    let task_watcher_node_id = node_id.clone();
+    let task_watcher_config = config.clone();
     tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(10));
         let mut last_task: Option<TaskConfig> = None;
-        
-        loop {
-            interval.tick().await;
-            match task_watcher_client.get_task().await {
-                Ok(new_task) => {
-                    let changed = match (&last_task, &new_task) {
-                        (Some(old), Some(new)) => old.task_name != new.task_name,
-                        (None, Some(_)) => true,
-                        (Some(_), None) => true,
-                        (None, None) => false,
-                    };
+        let mut etag: Option<String> = None;
+        let mut long_poll = true;
 
-                    if changed {
-                        info!(
-                            "Task changed: {:?} -> {:?}",
-                            last_task.as_ref().map(|t| &t.task_name),
-                            new_task.as_ref().map(|t| &t.task_name)
-                        );
-                        last_task = new_task.clone();
-                        // 通知所有工作线程任务变化
-                        let _ = task_tx_for_watcher.lock().await.send(new_task);
-                    }
-                }
-                Err(e) => {
-                    warn!("Failed to get task: {}", e);
-                }
-            }
-        }
-    });
-
-    // 启动工作容器
-    let mut container_handles = vec![];
-    let stop_timeout = config.stop_timeout; // 停止容器的超时时间
-
-    for instance_id in 0..parallelism {
-        let docker = docker.clone();
-        let mut task_rx = task_tx.lock().await.subscribe();
-        let active_containers = active_containers.clone();
-        let container_errors = container_errors.clone();
-        let node_id = node_id.clone();
-        let stop_requested_worker = stop_requested.clone();
-        let container_memory = config.container_memory;
-
-        let handle = tokio::spawn(async move {
-            let mut last_task_name: Option<String> = None;
-            let mut consecutive_failures: u32 = 0;
-            let stop_timeout = stop_timeout;
-            let container_memory = container_memory;
-            let mut current_container_id: Option<String> = None;
-
-            loop {
-                // 检查是否需要停止
-                if stop_requested_worker.load(Ordering::SeqCst) {
-                    info!("[Instance {}] Stop requested, cleaning up...", instance_id);
-                    // 如果有运行的容器，停止它
-                    if let Some(ref container_id) = current_container_id {
-                        info!("[Instance {}] Stopping container {}...", instance_id, container_id);
-                        if let Err(e) = docker.stop_container(container_id, stop_timeout).await {
-                            warn!("[Instance {}] Failed to stop container: {}", instance_id, e);
+        loop {
+            let new_task = if long_poll {
+                match task_watcher_client
+                    .watch_task(&task_watcher_node_id, etag.as_deref(), TASK_WATCH_WAIT_SECS)
+                    .await
+                {
+                    Ok(None) => continue,
+                    Ok(Some(TaskUpdate {
+                        task,
+                        etag: new_etag,
+                    })) => {
+                        if new_etag.is_none() {
+                            warn!("ComputeHub does not support long polling, polling every {}s", TASK_POLL_INTERVAL_SECS);
+                            long_poll = false;
                         }
-                        let _ = docker.remove_container(container_id).await;
-                        // 标记为已处理
-                        let _ = current_container_id.take();
+                        etag = new_etag;
+                        task
                     }
-                    break;
-                }
-                
-                // 获取当前任务
-                let task_opt = task_rx.borrow().clone();
-
-                match task_opt {
-                    Some(task) => {
-                        // 检查是否是新任务
-                        let is_new_task = last_task_name.as_ref() != Some(&task.task_name);
-
-                        if is_new_task {
-                            info!("[Instance {}] Starting task '{}' (previous failures: {})", instance_id, task.task_name, consecutive_failures);
-                            last_task_name = Some(task.task_name.clone());
-                            // 清除之前的错误状态，重置失败计数
-                            consecutive_failures = 0;
-                            container_errors.lock().await.remove(&instance_id);
-
-                            // 拉取镜像（带重试）
-                            if let Err(e) = pull_image_with_retry(&docker, &task.image, 3).await {
-                                error!("[Instance {}] Failed to pull image after retries: {}", instance_id, e);
-                                consecutive_failures += 1;
-                                container_errors.lock().await.insert(
-                                    instance_id,
-                                    format!("Image pull failed: {}", e),
-                                );
-                                // 可中断的等待
-                                if interruptible_sleep(Duration::from_secs(30), &mut task_rx).await {
-                                    info!("[Instance {}] Sleep interrupted by task change", instance_id);
-                                }
+                    Err(e) => {
+                        // 长轮询失败（如网络中断或代理切断长连接），本轮改用普通轮询
+                        warn!("Failed to watch task: {}, falling back to polling", e);
+                        sleep(Duration::from_secs(TASK_POLL_INTERVAL_SECS)).await;
+                        match task_watcher_client.get_task(&task_watcher_node_id).await {
+                            Ok(task) => task,
+                            Err(e) => {
+                                warn!("Failed to get task: {}", e);
                                 continue;
                             }
-
-                            // 准备环境变量
-                            let mut env = HashMap::new();
-                            env.insert("TASK_NAME".to_string(), task.task_name.clone());
-                            env.insert("NODE_ID".to_string(), node_id.clone());
-                            env.insert("INSTANCE_ID".to_string(), instance_id.to_string());
-
-                            if let Some(url) = task.input_redis {
-                                env.insert("INPUT_REDIS_URL".to_string(), url);
-                            }
-                            if let Some(url) = task.output_redis {
-                                env.insert("OUTPUT_REDIS_URL".to_string(), url);
-                            }
-                            if let Some(queue) = task.input_queue {
-                                env.insert("INPUT_QUEUE".to_string(), queue);
-                            }
-                            if let Some(queue) = task.output_queue {
-                                env.insert("OUTPUT_QUEUE".to_string(), queue);
-                            }
-
-                            // 启动容器（带重试）
-                            let mut container_started = false;
-                            for attempt in 1..=3 {
-                                match docker
-                                    .start_container(
-                                        &task.task_name,
-                                        &task.image,
-                                        &node_id,
-                                        instance_id as usize,
-                                        env.clone(),
-                                        container_memory,
-                                    )
-                                    .await
-                                {
-                                    Ok(container_id) => {
-                                        // 保存容器ID（用于优雅停止时停止容器）
-                                        current_container_id = Some(container_id.clone());
-                                        
-                                        // 标记活跃
-                                        *active_containers.lock().await += 1;
-                                        container_errors.lock().await.remove(&instance_id);
-
-                                        // 等待容器完成或任务变化
-                                        let exit_code = wait_container_or_task_change(
-                                            &docker,
-                                            &container_id,
-                                            &mut task_rx,
-                                            instance_id,
-                                            stop_timeout,
-                                        ).await;
-
-                                        // 清除容器ID
-                                        current_container_id = None;
-                                        
-                                        // 标记不活跃
-                                        *active_containers.lock().await -= 1;
-
-                                        if exit_code == -2 {
-                                            // 任务变化导致的停止
-                                            info!("[Instance {}] Container stopped due to task change", instance_id);
-                                            consecutive_failures = 0;
-                                            container_errors.lock().await.remove(&instance_id);
-                                            // 清理容器
-                                            let _ = docker.remove_container(&container_id).await;
-                                        } else if exit_code != 0 {
-                                            warn!("[Instance {}] Container exited with code {}", instance_id, exit_code);
-                                            consecutive_failures += 1;
-                                            container_errors.lock().await.insert(
-                                                instance_id,
-                                                format!("Container exited with code {}", exit_code),
-                                            );
-                                        } else {
-                                            // 成功完成，重置失败计数
-                                            consecutive_failures = 0;
-                                            container_errors.lock().await.remove(&instance_id);
-                                        }
-
-                                        container_started = true;
-                                        break;
-                                    }
-                                    Err(e) => {
-                                        error!("[Instance {}] Failed to start container (attempt {}/3): {}", instance_id, attempt, e);
-                                        if attempt < 3 {
-                                            // 可中断的等待
-                                            if interruptible_sleep(Duration::from_secs(5), &mut task_rx).await {
-                                                info!("[Instance {}] Retry sleep interrupted by task change", instance_id);
-                                                break;  // 跳出重试循环，让外层处理新任务
-                                            }
-                                        }
-                                    }
-                                }
-                            }
-
-                            if !container_started {
-                                error!("[Instance {}] Failed to start container after 3 attempts", instance_id);
-                                consecutive_failures += 1;
-                                container_errors.lock().await.insert(
-                                    instance_id,
-                                    "Failed to start container after 3 attempts".to_string(),
-                                );
-                                
-                                // 如果连续失败太多，增加等待时间（可中断）
-                                let backoff_secs = std::cmp::min(60u64, (10 * consecutive_failures) as u64);
-                                warn!("[Instance {}] Backing off for {} seconds due to repeated failures", instance_id, backoff_secs);
-                                if interruptible_sleep(Duration::from_secs(backoff_secs), &mut task_rx).await {
-                                    info!("[Instance {}] Backoff interrupted by task change", instance_id);
-                                }
-                            }
                         }
                     }
-                    None => {
-                        // 没有任务，等待
-                        if last_task_name.is_some() {
-                            info!("[Instance {}] No task assigned, waiting...", instance_id);
-                            last_task_name = None;
-                            consecutive_failures = 0;
-                            container_errors.lock().await.remove(&instance_id);
-                        }
-                        // 可中断的等待
-                        if interruptible_sleep(Duration::from_secs(5), &mut task_rx).await {
-                            info!("[Instance {}] Idle wait interrupted by task change", instance_id);
-                        }
+                }
+            } else {
+                sleep(Duration::from_secs(TASK_POLL_INTERVAL_SECS)).await;
+                match task_watcher_client.get_task(&task_watcher_node_id).await {
+                    Ok(task) => task,
+                    Err(e) => {
+                        warn!("Failed to get task: {}", e);
+                        continue;
                     }
                 }
+            };
 
-                // 短暂休息避免忙等
-                sleep(Duration::from_millis(100)).await;
+            let new_task = new_task.map(|task| task_watcher_config.limit_task(task));
+            if task_changed(last_task.as_ref(), new_task.as_ref()) {
+                info!(
+                    "Task changed: {:?} -> {:?}",
+                    last_task.as_ref().map(|t| t.revision_key()),
+                    new_task.as_ref().map(|t| t.revision_key())
+                );
+                last_task = new_task.clone();
+                // 通知所有工作线程任务变化
+                let _ = task_tx_for_watcher.lock().await.send(new_task);
             }
-        });
+        }
+    });
 
-        container_handles.push(handle);
-    }
+    // 启动工作实例，之后按 ComputeHub 设置的并行度增减
+    let ctx = WorkerContext {
+        docker: docker.clone(),
+        task_tx: task_tx.clone(),
+        active_containers: active_containers.clone(),
+        instances: instances.clone(),
+        node_id: node_id.clone(),
+        node_config: config.clone(),
+        stop_requested: stop_requested.clone(),
+        draining: draining.clone(),
+    };
+    let mut workers: Vec<Worker> = Vec::new();
 
     // 等待停止信号或所有工作线程
     loop {
         if stop_requested.load(Ordering::SeqCst) {
             info!("Waiting for all workers to stop...");
-            for handle in container_handles {
-                let _ = handle.await;
+            for worker in workers {
+                let _ = worker.handle.await;
             }
             
             info!("All workers stopped, cleaning up...");
@@ -485,13 +415,39 @@ async fn main() -> anyhow::Result<()> {
             // 注意：这会比较激进，默认不启用
             // let _ = cleanup_all_images(&docker).await;
             
+            if rejected.load(Ordering::SeqCst) {
+                anyhow::bail!("Node was rejected by ComputeHub (revoked)");
+            }
             info!("GridNode shutdown complete");
             break;
         }
+
+        // 并行度调低时，编号最大的实例退役；退役实例退出后才能以相同编号重新启动
+        let desired = desired_parallelism.load(Ordering::SeqCst);
+        while workers
+            .last()
+            .is_some_and(|w| w.retire.load(Ordering::SeqCst) && w.handle.is_finished())
+        {
+            workers.pop();
+        }
+        for worker in workers.iter().skip(desired as usize) {
+            if !worker.retire.swap(true, Ordering::SeqCst) {
+                info!("Parallelism lowered to {}, retiring a worker instance", desired);
+            }
+        }
+        let retiring = workers.iter().any(|w| w.retire.load(Ordering::SeqCst));
+        if !retiring && (workers.len() as u32) < desired {
+            if !workers.is_empty() {
+                info!("Parallelism raised to {}, starting {} worker instance(s)", desired, desired - workers.len() as u32);
+            }
+            for instance_id in workers.len() as u32..desired {
+                workers.push(Worker::spawn(instance_id, &ctx).await);
+            }
+        }
         
         // 检查是否所有工作线程都异常退出了
-        let all_finished = container_handles.iter().all(|h| h.is_finished());
-        if all_finished {
+        let all_finished = workers.iter().all(|w| w.handle.is_finished());
+        if all_finished && !workers.is_empty() {
             error!("All workers unexpectedly finished, exiting...");
             break;
         }
@@ -502,6 +458,325 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// 各工作实例的容器状态（instance_id -> 状态），随心跳上报
+type InstanceTable = Arc<Mutex<HashMap<u32, InstanceStatus>>>;
+
+/// 工作实例共享的状态
+#[derive(Clone)]
+struct WorkerContext {
+    docker: Arc<DockerManager>,
+    task_tx: Arc<Mutex<watch::Sender<Option<TaskConfig>>>>,
+    active_containers: Arc<Mutex<u32>>,
+    instances: InstanceTable,
+    node_id: String,
+    node_config: GridNodeConfig,
+    stop_requested: Arc<AtomicBool>,
+    draining: Arc<AtomicBool>,
+}
+
+/// 运行中的工作实例
+struct Worker {
+    handle: JoinHandle<()>,
+    /// 置为 true 后实例优雅停止容器并退出（并行度调低）
+    retire: Arc<AtomicBool>,
+}
+
+impl Worker {
+    async fn spawn(instance_id: u32, ctx: &WorkerContext) -> Self {
+        let task_rx = ctx.task_tx.lock().await.subscribe();
+        let retire = Arc::new(AtomicBool::new(false));
+        let handle = tokio::spawn(run_worker(instance_id, ctx.clone(), task_rx, retire.clone()));
+        Self { handle, retire }
+    }
+}
+
+/// 工作实例：按分配的任务启动容器，任务变化时重启容器，停止或退役时清理后退出
+async fn run_worker(
+    instance_id: u32,
+    ctx: WorkerContext,
+    mut task_rx: watch::Receiver<Option<TaskConfig>>,
+    retire: Arc<AtomicBool>,
+) {
+    let WorkerContext {
+        docker,
+        active_containers,
+        instances,
+        node_id,
+        node_config,
+        stop_requested,
+        draining,
+        ..
+    } = ctx;
+    let stop_timeout = node_config.stop_timeout; // 停止容器的超时时间
+
+    // 上次启动的任务（任务名 + 修订号）
+    let mut last_task_key: Option<(String, u64)> = None;
+    let mut consecutive_failures: u32 = 0;
+    let mut current_container_id: Option<String> = None;
+
+    // 启动时登记为空闲，没有分配任务的实例也出现在节点详情中
+    update_instance(&instances, instance_id, |_| {}).await;
+
+    loop {
+        // 检查是否需要停止（节点停止或并行度调低后本实例退役）
+        let retiring = retire.load(Ordering::SeqCst);
+        if retiring || stop_requested.load(Ordering::SeqCst) {
+            if retiring {
+                info!("[Instance {}] Retired by parallelism change, cleaning up...", instance_id);
+            } else {
+                info!("[Instance {}] Stop requested, cleaning up...", instance_id);
+            }
+            // 如果有运行的容器，停止它
+            if let Some(ref container_id) = current_container_id {
+                info!("[Instance {}] Stopping container {}...", instance_id, container_id);
+                if let Err(e) = docker.stop_container(container_id, stop_timeout).await {
+                    warn!("[Instance {}] Failed to stop container: {}", instance_id, e);
+                }
+                let _ = docker.remove_container(container_id).await;
+                // 标记为已处理
+                let _ = current_container_id.take();
+            }
+            break;
+        }
+        
+        // 获取当前任务（超出分配容器数的实例保持空闲）
+        let task_opt = task_for_instance(&task_rx.borrow(), instance_id).cloned();
+
+        match task_opt {
+            Some(task) => {
+                // 检查是否是新任务（或同一任务的新修订）
+                let task_key = task.revision_key();
+                let is_new_task = last_task_key.as_ref() != Some(&task_key);
+
+                // 排空时不启动新容器，取消排空后再启动（不记录 last_task_key）
+                if is_new_task && draining.load(Ordering::SeqCst) {
+                    if interruptible_sleep(Duration::from_secs(5), &mut task_rx).await {
+                        info!("[Instance {}] Drain wait interrupted by task change", instance_id);
+                    }
+                } else if is_new_task {
+                    info!("[Instance {}] Starting task '{}' revision {} (previous failures: {})", instance_id, task.task_name, task.revision, consecutive_failures);
+                    last_task_key = Some(task_key);
+                    // 清除之前的错误状态，重置失败计数
+                    consecutive_failures = 0;
+                    update_instance(&instances, instance_id, |status| {
+                        *status = InstanceStatus {
+                            instance_id,
+                            state: InstanceState::Pulling,
+                            task: Some(task.task_name.clone()),
+                            image: Some(task.image.clone()),
+                            ..Default::default()
+                        };
+                    })
+                    .await;
+
+                    // 拉取镜像（带重试）
+                    if let Err(e) = pull_image_with_retry(&docker, &task.image, 3).await {
+                        error!("[Instance {}] Failed to pull image after retries: {}", instance_id, e);
+                        consecutive_failures += 1;
+                        update_instance(&instances, instance_id, |status| {
+                            status.state = InstanceState::Failed;
+                            status.error = Some(format!("Image pull failed: {}", e));
+                        })
+                        .await;
+                        // 可中断的等待
+                        if interruptible_sleep(Duration::from_secs(30), &mut task_rx).await {
+                            info!("[Instance {}] Sleep interrupted by task change", instance_id);
+                        }
+                        continue;
+                    }
+
+                    // 准备环境变量
+                    let mut env = HashMap::new();
+                    env.insert("TASK_NAME".to_string(), task.task_name.clone());
+                    env.insert("NODE_ID".to_string(), node_id.clone());
+                    env.insert("INSTANCE_ID".to_string(), instance_id.to_string());
+
+                    if let Some(url) = task.input_redis {
+                        env.insert("INPUT_REDIS_URL".to_string(), url);
+                    }
+                    if let Some(url) = task.output_redis {
+                        env.insert("OUTPUT_REDIS_URL".to_string(), url);
+                    }
+                    if let Some(queue) = task.input_queue {
+                        env.insert("INPUT_QUEUE".to_string(), queue);
+                    }
+                    if let Some(queue) = task.output_queue {
+                        env.insert("OUTPUT_QUEUE".to_string(), queue);
+                    }
+
+                    // 容器资源（任务需求按本节点上限裁剪）、自定义环境变量和启动命令
+                    let spec = ContainerSpec {
+                        resources: node_config.container_resources(task.resources.as_ref()),
+                        env: task.env.clone().unwrap_or_default(),
+                        command: task.command.clone(),
+                        entrypoint: task.entrypoint.clone(),
+                    };
+                    info!(
+                        "[Instance {}] Container resources: {} CPUs, {} MB memory, pids {:?}, shm {:?} MB",
+                        instance_id,
+                        spec.resources.cpus,
+                        spec.resources.memory_mb,
+                        spec.resources.pids_limit,
+                        spec.resources.shm_size_mb
+                    );
+
+                    // 启动容器（带重试）
+                    let mut container_started = false;
+                    for attempt in 1..=3 {
+                        update_instance(&instances, instance_id, |status| status.restart_count = attempt - 1).await;
+                        match docker
+                            .start_container(
+                                &task.task_name,
+                                &task.image,
+                                &node_id,
+                                instance_id as usize,
+                                env.clone(),
+                                &spec,
+                            )
+                            .await
+                        {
+                            Ok(container_id) => {
+                                // 保存容器ID（用于优雅停止时停止容器）
+                                current_container_id = Some(container_id.clone());
+                                
+                                // 标记活跃
+                                *active_containers.lock().await += 1;
+                                update_instance(&instances, instance_id, |status| {
+                                    status.state = InstanceState::Running;
+                                    status.container_id = Some(container_id.clone());
+                                    status.error = None;
+                                })
+                                .await;
+
+                                // 等待容器完成或任务变化
+                                let exit_code = wait_container_or_task_change(
+                                    &docker,
+                                    &container_id,
+                                    &mut task_rx,
+                                    instance_id,
+                                    stop_timeout,
+                                    &retire,
+                                ).await;
+
+                                // 清除容器ID
+                                current_container_id = None;
+                                
+                                // 标记不活跃
+                                *active_containers.lock().await -= 1;
+
+                                if exit_code == -2 || exit_code == -3 {
+                                    // 任务变化或实例退役导致的停止
+                                    info!("[Instance {}] Container stopped due to task change or retirement", instance_id);
+                                    consecutive_failures = 0;
+                                    update_instance(&instances, instance_id, |status| {
+                                        status.state = InstanceState::Idle;
+                                        status.container_id = None;
+                                    })
+                                    .await;
+                                    // 清理容器
+                                    let _ = docker.remove_container(&container_id).await;
+                                } else if exit_code != 0 {
+                                    warn!("[Instance {}] Container exited with code {}", instance_id, exit_code);
+                                    consecutive_failures += 1;
+                                    update_instance(&instances, instance_id, |status| {
+                                        status.state = InstanceState::Exited;
+                                        status.container_id = None;
+                                        status.last_exit_code = Some(exit_code);
+                                        status.error = Some(format!("Container exited with code {}", exit_code));
+                                    })
+                                    .await;
+                                } else {
+                                    // 成功完成，重置失败计数
+                                    consecutive_failures = 0;
+                                    update_instance(&instances, instance_id, |status| {
+                                        status.state = InstanceState::Exited;
+                                        status.container_id = None;
+                                        status.last_exit_code = Some(exit_code);
+                                    })
+                                    .await;
+                                }
+
+                                container_started = true;
+                                break;
+                            }
+                            Err(e) => {
+                                error!("[Instance {}] Failed to start container (attempt {}/3): {}", instance_id, attempt, e);
+                                update_instance(&instances, instance_id, |status| {
+                                    status.error = Some(format!("Failed to start container: {}", e));
+                                })
+                                .await;
+                                if attempt < 3 {
+                                    // 可中断的等待
+                                    if interruptible_sleep(Duration::from_secs(5), &mut task_rx).await {
+                                        info!("[Instance {}] Retry sleep interrupted by task change", instance_id);
+                                        break;  // 跳出重试循环，让外层处理新任务
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    if !container_started {
+                        error!("[Instance {}] Failed to start container after 3 attempts", instance_id);
+                        consecutive_failures += 1;
+                        update_instance(&instances, instance_id, |status| {
+                            status.state = InstanceState::Failed;
+                            status.error = Some("Failed to start container after 3 attempts".to_string());
+                        })
+                        .await;
+                        
+                        // 如果连续失败太多，增加等待时间（可中断）
+                        let backoff_secs = std::cmp::min(60u64, (10 * consecutive_failures) as u64);
+                        warn!("[Instance {}] Backing off for {} seconds due to repeated failures", instance_id, backoff_secs);
+                        if interruptible_sleep(Duration::from_secs(backoff_secs), &mut task_rx).await {
+                            info!("[Instance {}] Backoff interrupted by task change", instance_id);
+                        }
+                    }
+                }
+            }
+            None => {
+                // 没有任务，等待
+                if last_task_key.is_some() {
+                    info!("[Instance {}] No task assigned, waiting...", instance_id);
+                    last_task_key = None;
+                    consecutive_failures = 0;
+                    update_instance(&instances, instance_id, |status| {
+                        *status = InstanceStatus {
+                            instance_id,
+                            ..Default::default()
+                        };
+                    })
+                    .await;
+                }
+                // 可中断的等待
+                if interruptible_sleep(Duration::from_secs(5), &mut task_rx).await {
+                    info!("[Instance {}] Idle wait interrupted by task change", instance_id);
+                }
+            }
+        }
+
+        // 短暂休息避免忙等
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    // 退出的实例不再上报
+    instances.lock().await.remove(&instance_id);
+}
+
+/// 更新工作实例的状态（不存在时先创建空闲状态）
+async fn update_instance(
+    instances: &InstanceTable,
+    instance_id: u32,
+    update: impl FnOnce(&mut InstanceStatus),
+) {
+    let mut instances = instances.lock().await;
+    let status = instances.entry(instance_id).or_insert_with(|| InstanceStatus {
+        instance_id,
+        ..Default::default()
+    });
+    update(status);
+}
+
 /// 初始化 Docker 管理器（带重试）
 async fn init_docker_with_retry() -> anyhow::Result<DockerManager> {
     let mut last_error = None;
@@ -549,41 +824,53 @@ async fn pull_image_with_retry(
     Err(anyhow::anyhow!("Failed to pull image after {} attempts", max_retries))
 }
 
-/// 等待容器完成或任务变化
-/// 返回: 容器退出码，或 -2 表示任务变化导致的停止
+/// 等待容器完成、任务变化或实例退役
+/// 返回: 容器退出码，-2 表示任务变化导致的停止，-3 表示实例退役导致的停止
 async fn wait_container_or_task_change(
     docker: &DockerManager,
     container_id: &str,
     task_rx: &mut watch::Receiver<Option<TaskConfig>>,
     instance_id: u32,
     stop_timeout: u64,
+    retire: &AtomicBool,
 ) -> i64 {
     use tokio::time::timeout;
     
-    // 保存当前任务名用于比较
-    let current_task_name = task_rx.borrow().as_ref().map(|t| t.task_name.clone());
+    // 保存当前任务名和修订号用于比较
+    let current_task_key =
+        task_for_instance(&task_rx.borrow(), instance_id).map(|t| t.revision_key());
     
     loop {
         // 检查任务是否变化（非阻塞）
-        match task_rx.has_changed() {
-            Ok(true) => {
-                task_rx.mark_changed();
-                let new_task = task_rx.borrow().clone();
-                let new_name = new_task.as_ref().map(|t| t.task_name.clone());
-                
-                if new_name != current_task_name {
-                    info!(
-                        "[Instance {}] Task changed from {:?} to {:?}, stopping container (timeout: {}s)",
-                        instance_id, current_task_name, new_name, stop_timeout
-                    );
-                    // 停止容器（使用配置的超时时间）
-                    if let Err(e) = docker.stop_container(container_id, stop_timeout).await {
-                        warn!("[Instance {}] Failed to stop container: {}", instance_id, e);
-                    }
-                    return -2; // 任务变化标记
+        // 本实例不再参与当前任务（容器数被调低）或任务修订号变化也视为任务变化
+        if let Ok(true) = task_rx.has_changed() {
+            task_rx.mark_changed();
+            let new_key =
+                task_for_instance(&task_rx.borrow(), instance_id).map(|t| t.revision_key());
+            
+            if new_key != current_task_key {
+                info!(
+                    "[Instance {}] Task changed from {:?} to {:?}, stopping container (timeout: {}s)",
+                    instance_id, current_task_key, new_key, stop_timeout
+                );
+                // 停止容器（使用配置的超时时间）
+                if let Err(e) = docker.stop_container(container_id, stop_timeout).await {
+                    warn!("[Instance {}] Failed to stop container: {}", instance_id, e);
                 }
+                return -2; // 任务变化标记
             }
-            _ => {}
+        }
+
+        // 并行度调低，本实例退役
+        if retire.load(Ordering::SeqCst) {
+            info!(
+                "[Instance {}] Retiring, stopping container (timeout: {}s)",
+                instance_id, stop_timeout
+            );
+            if let Err(e) = docker.stop_container(container_id, stop_timeout).await {
+                warn!("[Instance {}] Failed to stop container: {}", instance_id, e);
+            }
+            return -3; // 实例退役标记
         }
         
         // 检查容器状态（每100ms检查一次）
@@ -603,6 +890,25 @@ async fn wait_container_or_task_change(
     }
 }
 
+/// 任务配置是否变化（任务名、修订号或分配的容器数）
+fn task_changed(old: Option<&TaskConfig>, new: Option<&TaskConfig>) -> bool {
+    match (old, new) {
+        (Some(old), Some(new)) => {
+            old.task_name != new.task_name
+                || old.revision != new.revision
+                || old.instances != new.instances
+        }
+        (None, None) => false,
+        _ => true,
+    }
+}
+
+/// 获取指定实例应执行的任务
+/// 任务限制了本节点的容器数且实例 ID 超出时返回 None
+fn task_for_instance(task: &Option<TaskConfig>, instance_id: u32) -> Option<&TaskConfig> {
+    task.as_ref().filter(|t| t.runs_on_instance(instance_id))
+}
+
 /// 可中断的睡眠
 /// 返回: true 表示被任务变化中断，false 表示正常完成
 async fn interruptible_sleep(
@@ -611,21 +917,15 @@ async fn interruptible_sleep(
 ) -> bool {
     let sleep = tokio::time::sleep(duration);
     tokio::pin!(sleep);
-    
-    loop {
-        tokio::select! {
-            _ = &mut sleep => {
-                // 正常完成睡眠
-                return false;
-            }
-            result = task_rx.changed() => {
-                // channel 关闭或出错，视为中断
-                if result.is_err() {
-                    return true;
-                }
-                // 检查任务是否真的变化了
-                return true;
-            }
+
+    tokio::select! {
+        _ = &mut sleep => {
+            // 正常完成睡眠
+            false
+        }
+        _ = task_rx.changed() => {
+            // 任务变化，或 channel 关闭/出错，均视为中断
+            true
         }
     }
 }
@@ -0,0 +1,198 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+const AUDIT_FILENAME: &str = "audit.jsonl";
+
+/// 一条审计记录：谁（哪个管理员密钥）在什么时间做了什么，以及操作对象在操作前后的状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub at: DateTime<Utc>,
+    /// 管理员密钥名称（admin_keys 中的名称，旧版 token 为 "token"）
+    pub actor: String,
+    /// 操作，如 create_task、finish_task、stop_node
+    pub action: String,
+    /// 操作对象（任务名或节点 ID）
+    pub target: String,
+    /// 操作前的状态（之前不存在时省略）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub before: Option<serde_json::Value>,
+    /// 操作后的状态（操作后不存在时省略，如删除）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<serde_json::Value>,
+}
+
+/// 审计日志（state_dir/audit.jsonl）
+/// 只追加，不随状态日志压缩
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+}
+
+impl AuditLog {
+    /// 打开（或创建）审计日志
+    pub fn open<P: AsRef<Path>>(dir: P) -> anyhow::Result<Self> {
+        let dir = dir.as_ref();
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(AUDIT_FILENAME);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self { path, file })
+    }
+
+    /// 追加一条记录并落盘
+    pub fn append(&mut self, record: &AuditRecord) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// 审计日志文件路径（查询时重新打开，不占用写入句柄）
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// 按时间范围查询（since <= at < until），按时间顺序返回最近的 limit 条
+/// 从文件末尾向前读取，凑满 limit 条后停止；会阻塞，应在 spawn_blocking 中调用
+pub fn query(
+    path: &Path,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    limit: usize,
+) -> anyhow::Result<Vec<AuditRecord>> {
+    let mut records = Vec::new();
+    if limit == 0 {
+        return Ok(records);
+    }
+    for line in ReverseLines::open(path)? {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: AuditRecord = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Skipping corrupt audit line: {}", e);
+                continue;
+            }
+        };
+        if since.is_some_and(|since| record.at < since)
+            || until.is_some_and(|until| record.at >= until)
+        {
+            continue;
+        }
+        records.push(record);
+        if records.len() >= limit {
+            break;
+        }
+    }
+    records.reverse();
+    Ok(records)
+}
+
+/// 每次向前读取的字节数
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+/// 从文件末尾向前逐行读取
+struct ReverseLines {
+    file: File,
+    /// 尚未读取部分的结尾位置
+    pos: u64,
+    /// 已读取但尚未返回的内容（从 pos 开始）
+    buf: Vec<u8>,
+}
+
+impl ReverseLines {
+    fn open(path: &Path) -> std::io::Result<Self> {
+        let file = File::open(path)?;
+        let pos = file.metadata()?.len();
+        Ok(Self {
+            file,
+            pos,
+            buf: Vec::new(),
+        })
+    }
+}
+
+impl Iterator for ReverseLines {
+    type Item = std::io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(newline) = self.buf.iter().rposition(|&b| b == b'\n') {
+                let line = self.buf.split_off(newline + 1);
+                self.buf.truncate(newline);
+                return Some(Ok(String::from_utf8_lossy(&line).into_owned()));
+            }
+            if self.pos == 0 {
+                if self.buf.is_empty() {
+                    return None;
+                }
+                let line = std::mem::take(&mut self.buf);
+                return Some(Ok(String::from_utf8_lossy(&line).into_owned()));
+            }
+
+            let len = self.pos.min(CHUNK_SIZE);
+            self.pos -= len;
+            let mut chunk = vec![0; len as usize];
+            if let Err(e) = self
+                .file
+                .seek(SeekFrom::Start(self.pos))
+                .and_then(|_| self.file.read_exact(&mut chunk))
+            {
+                return Some(Err(e));
+            }
+            chunk.append(&mut self.buf);
+            self.buf = chunk;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn record(at: DateTime<Utc>, target: usize) -> AuditRecord {
+        AuditRecord {
+            at,
+            actor: "ops".to_string(),
+            action: "create_task".to_string(),
+            target: format!("task-{}", target),
+            before: None,
+            after: None,
+        }
+    }
+
+    fn targets(records: &[AuditRecord]) -> Vec<&str> {
+        records.iter().map(|record| record.target.as_str()).collect()
+    }
+
+    #[test]
+    fn query_returns_most_recent_records_in_range() {
+        let dir = std::env::temp_dir().join(format!("audit-{}", uuid::Uuid::new_v4()));
+        let mut audit = AuditLog::open(&dir).unwrap();
+        let start = Utc::now();
+        // 超过一个读取块，验证跨块拼接的行
+        for i in 0..2000 {
+            audit.append(&record(start + Duration::seconds(i as i64), i)).unwrap();
+        }
+
+        let latest = query(audit.path(), None, None, 3).unwrap();
+        assert_eq!(targets(&latest), ["task-1997", "task-1998", "task-1999"]);
+
+        let since = Some(start + Duration::seconds(10));
+        let until = Some(start + Duration::seconds(13));
+        let range = query(audit.path(), since, until, 100).unwrap();
+        assert_eq!(targets(&range), ["task-10", "task-11", "task-12"]);
+
+        assert_eq!(query(audit.path(), None, None, 5000).unwrap().len(), 2000);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
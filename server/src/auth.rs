@@ -5,47 +5,284 @@ use axum::{
     response::Response,
 };
 
-
+use crate::config::ServerConfig;
+use crate::models::AppStateInner;
+use crate::tls::ClientCertificate;
 use crate::AppState;
+use sha2::{Digest, Sha256};
+
+/// 凭据作用域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthScope {
+    /// 管理员接口（/api/*）
+    Admin,
+    /// 节点接口（/gridnode/*）
+    Node,
+}
+
+impl AuthScope {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuthScope::Admin => "admin",
+            AuthScope::Node => "node",
+        }
+    }
+}
+
+/// 节点接口请求使用的凭据（由 node_auth_middleware 放入请求扩展）
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NodeAuth {
+    /// 共享的节点密钥（node_keys 或旧版 token），用于首次注册和没有专属凭据的旧版节点
+    Bootstrap,
+    /// 注册时签发给该节点的专属凭据
+    Node(String),
+    /// 由 tls_client_ca 签发的客户端证书（值为证书 CN，即节点 ID）
+    Certificate(String),
+}
 
-/// 认证中间件
-/// 检查请求头中的 Authorization: Bearer <token>
-pub async fn auth_middleware(
+/// 通过认证的管理员密钥名称（由 admin_auth_middleware 放入请求扩展，用于审计日志）
+#[derive(Debug, Clone)]
+pub struct AdminKey(pub String);
+
+/// 管理员认证中间件（/api/*）
+pub async fn admin_auth_middleware(
     State(state): State<AppState>,
     request: Request,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    // 获取配置的 token
-    let expected_token = {
+    authenticate(state, AuthScope::Admin, request, next).await
+}
+
+/// 节点认证中间件（/gridnode/*）
+/// 接受客户端证书、共享的节点密钥或节点专属凭据，具体节点是否有权访问由 authorize_node 在处理函数中判断
+/// 出示了客户端证书时以证书身份为准，忽略 Authorization 头
+pub async fn node_auth_middleware(
+    State(state): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let cert_node_id = request
+        .extensions()
+        .get::<ClientCertificate>()
+        .and_then(|cert| cert.node_id.clone());
+    if let Some(node_id) = cert_node_id {
+        tracing::debug!("Authenticated node '{}' by client certificate", node_id);
+        request.extensions_mut().insert(NodeAuth::Certificate(node_id));
+        return Ok(next.run(request).await);
+    }
+
+    let Some(token) = bearer_token(&request) else {
+        tracing::warn!("Missing or invalid Authorization header");
+        state.read().await.metrics.auth_failure(AuthScope::Node.as_str(), "missing");
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let auth = {
         let state = state.read().await;
-        state.config.token.clone()
+        if find_key(&state.config, AuthScope::Node, token).is_some() {
+            Some(NodeAuth::Bootstrap)
+        } else {
+            state
+                .node_for_credential(&hash_secret(token))
+                .map(|node_id| NodeAuth::Node(node_id.to_string()))
+        }
     };
 
-    // 从请求头获取 token
-    let auth_header = request
-        .headers()
-        .get(header::AUTHORIZATION)
-        .and_then(|value| value.to_str().ok());
-
-    match auth_header {
-        Some(header) if header.starts_with("Bearer ") => {
-            let token = &header[7..]; // 跳过 "Bearer "
-            if token == expected_token {
-                Ok(next.run(request).await)
-            } else {
-                tracing::warn!("Invalid token provided");
-                Err(StatusCode::UNAUTHORIZED)
+    match auth {
+        Some(auth) => {
+            request.extensions_mut().insert(auth);
+            Ok(next.run(request).await)
+        }
+        None => {
+            tracing::warn!("Invalid node token provided");
+            state.read().await.metrics.auth_failure(AuthScope::Node.as_str(), "invalid");
+            Err(StatusCode::UNAUTHORIZED)
+        }
+    }
+}
+
+/// 检查请求凭据能否代表该节点
+/// - 已吊销的节点：403
+/// - 客户端证书：只能代表证书 CN 对应的节点
+/// - 通过客户端证书注册的节点：只接受证书，不接受共享的节点密钥或专属凭据
+/// - 持有专属凭据的节点：只接受该节点自己的凭据
+/// - 没有专属凭据的节点：只接受共享的节点密钥；allow_new_nodes 为 false 时不能注册新的节点 ID（403）
+pub fn authorize_node(
+    state: &AppStateInner,
+    auth: &NodeAuth,
+    node_id: &str,
+) -> Result<(), StatusCode> {
+    if state.is_node_revoked(node_id) {
+        tracing::warn!("Rejected request from revoked node {}", node_id);
+        state.metrics.auth_failure(AuthScope::Node.as_str(), "revoked");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if matches!(auth, NodeAuth::Bootstrap)
+        && !state.config.allow_new_nodes
+        && !state.nodes.contains_key(node_id)
+    {
+        tracing::warn!("Rejected bootstrap registration of new node {}", node_id);
+        state.metrics.auth_failure(AuthScope::Node.as_str(), "new_node");
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let allowed = match auth {
+        NodeAuth::Certificate(id) => id == node_id,
+        NodeAuth::Node(id) => id == node_id && !state.node_uses_certificate(node_id),
+        NodeAuth::Bootstrap => {
+            !state.node_has_credential(node_id) && !state.node_uses_certificate(node_id)
+        }
+    };
+    if allowed {
+        Ok(())
+    } else {
+        tracing::warn!("Credential does not match node {}", node_id);
+        state.metrics.auth_failure(AuthScope::Node.as_str(), "mismatch");
+        Err(StatusCode::UNAUTHORIZED)
+    }
+}
+
+/// 生成新的节点专属凭据（256 位随机数，十六进制）
+pub fn generate_node_secret() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// 计算密钥的 SHA-256（服务端只保存哈希）
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// 检查请求头中的 Authorization: Bearer <token> 是否为该作用域的有效密钥
+async fn authenticate(
+    state: AppState,
+    scope: AuthScope,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let Some(token) = bearer_token(&request) else {
+        tracing::warn!("Missing or invalid Authorization header");
+        state.read().await.metrics.auth_failure(scope.as_str(), "missing");
+        return Err(StatusCode::UNAUTHORIZED);
+    };
+
+    let key_name = {
+        let state = state.read().await;
+        find_key(&state.config, scope, token).map(str::to_string)
+    };
+
+    match key_name {
+        Some(name) => {
+            tracing::debug!("Authenticated with {} key '{}'", scope.as_str(), name);
+            if scope == AuthScope::Admin {
+                request.extensions_mut().insert(AdminKey(name));
             }
+            Ok(next.run(request).await)
         }
-        _ => {
-            tracing::warn!("Missing or invalid Authorization header");
+        None => {
+            tracing::warn!("Invalid {} token provided", scope.as_str());
+            state.read().await.metrics.auth_failure(scope.as_str(), "invalid");
             Err(StatusCode::UNAUTHORIZED)
         }
     }
 }
 
+/// 从请求头获取 Authorization: Bearer <token>
+fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|header| header.strip_prefix("Bearer "))
+}
+
+/// 查找与 token 匹配的密钥名称
+/// 旧版 token 名称记为 "token"：始终可以访问节点接口，只有没有配置分组密钥时才能访问管理员接口
+fn find_key<'a>(config: &'a ServerConfig, scope: AuthScope, token: &str) -> Option<&'a str> {
+    let keys = match scope {
+        AuthScope::Admin => &config.admin_keys,
+        AuthScope::Node => &config.node_keys,
+    };
+
+    // 遍历所有密钥，不提前返回，避免通过响应时间推断匹配位置
+    let mut found = None;
+    for (name, secret) in keys {
+        if constant_time_eq(secret.as_bytes(), token.as_bytes()) {
+            found = Some(name.as_str());
+        }
+    }
+    if let Some(legacy) = &config.token
+        && (scope == AuthScope::Node || config.legacy_token_is_admin())
+        && constant_time_eq(legacy.as_bytes(), token.as_bytes())
+    {
+        found = found.or(Some("token"));
+    }
+    found
+}
+
+/// 常量时间比较（长度不同时直接返回 false）
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /// 健康检查不需要认证
 pub async fn health_check() -> &'static str {
     tracing::info!("Health check requested");
     "OK"
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::AppStateInner;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+    use tower::ServiceExt;
+
+    /// 管理员接口和节点接口各一个路由，认证方式与 main 中相同
+    fn app(config: ServerConfig) -> Router {
+        let state: AppState = Arc::new(RwLock::new(AppStateInner::new(config)));
+        let admin = Router::new()
+            .route("/api/tasks", get(|| async { "admin" }))
+            .route_layer(middleware::from_fn_with_state(state.clone(), admin_auth_middleware));
+        let node = Router::new()
+            .route("/gridnode/task", get(|| async { "node" }))
+            .route_layer(middleware::from_fn_with_state(state.clone(), node_auth_middleware));
+        admin.merge(node).with_state(state)
+    }
+
+    async fn status(app: &Router, uri: &str, token: &str) -> StatusCode {
+        let request = axum::http::Request::get(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap();
+        app.clone().oneshot(request).await.unwrap().status()
+    }
+
+    #[tokio::test]
+    async fn legacy_token_is_node_only_once_scoped_keys_exist() {
+        let app = app(ServerConfig {
+            token: Some("legacy".to_string()),
+            admin_keys: HashMap::from([("ops".to_string(), "admin-secret".to_string())]),
+            ..ServerConfig::default()
+        });
+        assert_eq!(status(&app, "/api/tasks", "legacy").await, StatusCode::UNAUTHORIZED);
+        assert_eq!(status(&app, "/api/tasks", "admin-secret").await, StatusCode::OK);
+        assert_eq!(status(&app, "/gridnode/task", "legacy").await, StatusCode::OK);
+        assert_eq!(status(&app, "/gridnode/task", "admin-secret").await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn legacy_token_alone_grants_both_scopes() {
+        let app = app(ServerConfig {
+            token: Some("legacy".to_string()),
+            ..ServerConfig::default()
+        });
+        assert_eq!(status(&app, "/api/tasks", "legacy").await, StatusCode::OK);
+        assert_eq!(status(&app, "/gridnode/task", "legacy").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,131 @@
+use crate::models::{AppState, NodeStatus, TaskStatus};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use tokio::time::{interval, timeout, Duration};
+use tracing::{info, warn};
+
+/// 检查间隔（秒）
+const CHECK_INTERVAL_SECS: u64 = 15;
+
+/// Redis 查询超时（秒）
+const REDIS_TIMEOUT_SECS: u64 = 5;
+
+/// 需要检查的 Running 任务
+struct WatchedTask {
+    name: String,
+    redis_url: String,
+    queue: String,
+    grace_period_secs: u64,
+    /// 已有节点运行过该任务的容器，且分配到该任务的节点都没有活跃容器（策略不要求时恒为 true）
+    nodes_idle: bool,
+}
+
+/// 启动自动完成检查任务
+///
+/// 对设置了 auto_finish 的 Running 任务，定期查询输入队列长度（LLEN）。
+/// 队列持续为空超过 grace_period_secs，且已有节点运行过该任务的容器、执行该任务的节点都没有活跃容器时
+/// （可通过 require_idle_nodes 关闭），
+/// 自动调用 finish_current_task 完成该任务。
+pub fn spawn_auto_finish(state: AppState) {
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(CHECK_INTERVAL_SECS));
+        // 任务名 -> 首次观察到可以完成的时间
+        let mut drained_since: HashMap<String, DateTime<Utc>> = HashMap::new();
+
+        loop {
+            interval.tick().await;
+
+            let watched = collect_watched_tasks(&state).await;
+            drained_since.retain(|name, _| watched.iter().any(|task| &task.name == name));
+
+            for task in watched {
+                let drained = match queue_len(&task.redis_url, &task.queue).await {
+                    Ok(len) => len == 0 && task.nodes_idle,
+                    Err(e) => {
+                        warn!(
+                            "Auto-finish: failed to query queue '{}' for task '{}': {}",
+                            task.queue, task.name, e
+                        );
+                        continue;
+                    }
+                };
+
+                if !drained {
+                    drained_since.remove(&task.name);
+                    continue;
+                }
+
+                let now = Utc::now();
+                let since = *drained_since.entry(task.name.clone()).or_insert(now);
+                let elapsed = now.signed_duration_since(since).num_seconds();
+                if elapsed < task.grace_period_secs as i64 {
+                    continue;
+                }
+
+                let mut state = state.write().await;
+                match state.finish_current_task(Some(&task.name)) {
+                    Ok((completed, started)) => {
+                        info!(
+                            "Auto-finished '{}' (input queue empty for {}s), started {:?}",
+                            completed, elapsed, started
+                        );
+                    }
+                    Err(e) => warn!("Auto-finish of '{}' failed: {}", task.name, e),
+                }
+                drained_since.remove(&task.name);
+            }
+        }
+    });
+}
+
+/// 收集设置了 auto_finish 的 Running 任务
+async fn collect_watched_tasks(state: &AppState) -> Vec<WatchedTask> {
+    let state = state.read().await;
+
+    state
+        .tasks
+        .iter()
+        .filter(|entry| entry.status == TaskStatus::Running)
+        .filter_map(|entry| {
+            let task = &entry.task;
+            // create_task 保证设置了 auto_finish 的任务同时设置了 input_redis/input_queue
+            let policy = task.auto_finish.as_ref()?;
+            let redis_url = task.input_redis.as_ref()?;
+            let queue = task.input_queue.as_ref()?;
+            let nodes_idle = !policy.require_idle_nodes || {
+                let assigned: Vec<_> = state
+                    .nodes
+                    .values()
+                    .filter(|node| node.status == NodeStatus::Online)
+                    .filter(|node| node.assigned_task.as_deref() == Some(task.name.as_str()))
+                    .collect();
+                // 任务开始后必须有节点上报过该任务的容器在运行，否则节点可能还在拉取镜像、尚未开始执行
+                entry.first_run_at.is_some()
+                    && assigned.iter().all(|node| node.active_containers == 0)
+            };
+
+            Some(WatchedTask {
+                name: task.name.clone(),
+                redis_url: redis_url.clone(),
+                queue: queue.clone(),
+                grace_period_secs: policy.grace_period_secs,
+                nodes_idle,
+            })
+        })
+        .collect()
+}
+
+/// 查询 Redis 队列长度
+async fn queue_len(redis_url: &str, queue: &str) -> anyhow::Result<u64> {
+    let client = redis::Client::open(redis_url)?;
+    let query = async {
+        let mut conn = client.get_multiplexed_async_connection().await?;
+        let len: u64 = redis::cmd("LLEN").arg(queue).query_async(&mut conn).await?;
+        Ok::<_, redis::RedisError>(len)
+    };
+
+    match timeout(Duration::from_secs(REDIS_TIMEOUT_SECS), query).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(anyhow::anyhow!("Redis query timed out")),
+    }
+}
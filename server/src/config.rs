@@ -1,5 +1,9 @@
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::events::EVENT_TYPES;
+use crate::tls::TlsFiles;
 
 /// ComputeHub 配置文件
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -7,19 +11,129 @@ pub struct ServerConfig {
     /// 监听地址
     #[serde(default = "default_bind")]
     pub bind: String,
-    /// 节点认证 Token
-    pub token: String,
+    /// 旧版单一 Token（可选）：没有配置 admin_keys / node_keys 时同时作为管理员和节点凭据，
+    /// 配置了任一分组密钥后只作为节点凭据；建议改用 admin_keys / node_keys
+    #[serde(default)]
+    pub token: Option<String>,
+    /// 状态持久化目录（任务队列和节点注册表的快照与日志）
+    /// 默认为配置文件所在目录下的 state/
+    #[serde(default)]
+    pub state_dir: Option<String>,
+    /// 同时运行的任务数上限（默认 1，即一次只运行一个任务）
+    /// 大于 1 时，节点按任务 weight 公平分配给各 Running 任务
+    #[serde(default = "default_max_running_tasks")]
+    pub max_running_tasks: usize,
+    /// 管理员凭据（名称 -> 密钥），用于 /api/*
+    #[serde(default)]
+    pub admin_keys: HashMap<String, String>,
+    /// 节点凭据（名称 -> 密钥），用于 /gridnode/*
+    #[serde(default)]
+    pub node_keys: HashMap<String, String>,
+    /// 是否允许使用共享节点密钥注册新的节点 ID（默认 true）
+    /// 设为 false 后只有已知节点能使用共享密钥，新节点需要客户端证书
+    #[serde(default = "default_allow_new_nodes")]
+    pub allow_new_nodes: bool,
+    /// TLS 证书文件（PEM，可包含证书链）；与 tls_key 同时设置时启用 HTTPS
+    #[serde(default)]
+    pub tls_cert: Option<String>,
+    /// TLS 私钥文件（PEM）
+    #[serde(default)]
+    pub tls_key: Option<String>,
+    /// 签发 GridNode 客户端证书的 CA（PEM），设置后节点可以用客户端证书代替密钥认证
+    /// 证书主题的 CN 即节点 ID
+    #[serde(default)]
+    pub tls_client_ca: Option<String>,
+    /// 节点心跳超时（秒），超时后节点标记为 Offline，不再分配任务
+    #[serde(default = "default_node_timeout_secs")]
+    pub node_timeout_secs: u64,
+    /// Offline 节点的保留时间（秒），超过后从节点列表中删除
+    #[serde(default = "default_node_retention_secs")]
+    pub node_retention_secs: u64,
+    /// 检查节点心跳的间隔（秒）
+    #[serde(default = "default_node_check_interval_secs")]
+    pub node_check_interval_secs: u64,
+    /// 事件 webhook（任务结束、节点进入错误状态等时发送通知）
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+/// 配置热重载中变化的配置项
+#[derive(Debug, Default)]
+pub struct ConfigChanges {
+    /// 已立即生效的配置项
+    pub applied: Vec<&'static str>,
+    /// 需要重启 ComputeHub 才能生效的配置项
+    pub restart_required: Vec<&'static str>,
+}
+
+/// 事件 webhook 配置
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WebhookConfig {
+    /// 接收事件的地址（POST JSON）
+    pub url: String,
+    /// 只发送这些类型的事件（与 /api/events 的 type 相同），为空时发送全部事件
+    #[serde(default)]
+    pub events: Vec<String>,
+    /// HMAC-SHA256 签名密钥，设置后请求带 X-GridCore-Signature: sha256=<签名>
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// 最多尝试次数（失败后按 1, 2, 4... 秒退避重试，默认 5）
+    #[serde(default = "default_webhook_attempts")]
+    pub max_attempts: u32,
+}
+
+impl WebhookConfig {
+    /// 该 webhook 是否接收此类型的事件
+    pub fn matches(&self, event_type: &str) -> bool {
+        self.events.is_empty() || self.events.iter().any(|event| event == event_type)
+    }
+}
+
+fn default_webhook_attempts() -> u32 {
+    5
 }
 
 fn default_bind() -> String {
     "0.0.0.0:8080".to_string()
 }
 
+fn default_allow_new_nodes() -> bool {
+    true
+}
+
+fn default_max_running_tasks() -> usize {
+    1
+}
+
+fn default_node_timeout_secs() -> u64 {
+    60
+}
+
+fn default_node_retention_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_node_check_interval_secs() -> u64 {
+    30
+}
+
 impl Default for ServerConfig {
     fn default() -> Self {
         Self {
             bind: default_bind(),
-            token: "change-me-in-production".to_string(),
+            token: None,
+            state_dir: None,
+            max_running_tasks: default_max_running_tasks(),
+            admin_keys: HashMap::new(),
+            node_keys: HashMap::new(),
+            allow_new_nodes: default_allow_new_nodes(),
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            node_timeout_secs: default_node_timeout_secs(),
+            node_retention_secs: default_node_retention_secs(),
+            node_check_interval_secs: default_node_check_interval_secs(),
+            webhooks: Vec::new(),
         }
     }
 }
@@ -29,9 +143,193 @@ impl ServerConfig {
     pub fn from_file<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
         let content = std::fs::read_to_string(path)?;
         let config: ServerConfig = toml::from_str(&content)?;
+        config.validate_credentials()?;
+        config.tls_files()?;
+        config.validate_webhooks()?;
+        config.validate_liveness()?;
         Ok(config)
     }
 
+    /// 检查凭据配置：两个作用域都必须有可用的密钥，且同一个密钥不能同时出现在两个作用域
+    fn validate_credentials(&self) -> anyhow::Result<()> {
+        if self.admin_keys.is_empty() && !(self.token.is_some() && self.legacy_token_is_admin()) {
+            anyhow::bail!(
+                "No admin credentials configured (set admin_keys; token only grants admin access when no admin_keys/node_keys are configured)"
+            );
+        }
+        if self.token.is_none() && self.node_keys.is_empty() && self.tls_client_ca.is_none() {
+            anyhow::bail!("No node credentials configured (set node_keys, token or tls_client_ca)");
+        }
+        for (scope, keys) in [("admin_keys", &self.admin_keys), ("node_keys", &self.node_keys)] {
+            if let Some((name, _)) = keys.iter().find(|(_, secret)| secret.is_empty()) {
+                anyhow::bail!("Key '{}' in {} is empty", name, scope);
+            }
+        }
+        if let Some((name, _)) = self
+            .admin_keys
+            .iter()
+            .find(|(_, secret)| self.node_keys.values().any(|node| node == *secret))
+        {
+            anyhow::bail!("Admin key '{}' is also configured as a node key", name);
+        }
+        Ok(())
+    }
+
+    /// 检查节点心跳配置：超时和检查间隔必须大于 0
+    fn validate_liveness(&self) -> anyhow::Result<()> {
+        if self.node_timeout_secs == 0 {
+            anyhow::bail!("node_timeout_secs must be greater than 0");
+        }
+        if self.node_check_interval_secs == 0 {
+            anyhow::bail!("node_check_interval_secs must be greater than 0");
+        }
+        Ok(())
+    }
+
+    /// 检查 webhook 配置：地址必须是 http(s) URL，事件类型必须存在
+    fn validate_webhooks(&self) -> anyhow::Result<()> {
+        for webhook in &self.webhooks {
+            let url = reqwest::Url::parse(&webhook.url)
+                .map_err(|e| anyhow::anyhow!("Invalid webhook url '{}': {}", webhook.url, e))?;
+            if !matches!(url.scheme(), "http" | "https") {
+                anyhow::bail!("Webhook url '{}' must use http or https", webhook.url);
+            }
+            if let Some(event) = webhook
+                .events
+                .iter()
+                .find(|event| !EVENT_TYPES.contains(&event.as_str()))
+            {
+                anyhow::bail!(
+                    "Unknown event type '{}' for webhook {} (expected one of: {})",
+                    event,
+                    webhook.url,
+                    EVENT_TYPES.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// TLS 相关文件（未配置 TLS 时为 None，只配置了证书和私钥之一时报错）
+    pub fn tls_files(&self) -> anyhow::Result<Option<TlsFiles>> {
+        let (cert, key) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => {
+                if self.tls_client_ca.is_some() {
+                    anyhow::bail!("tls_client_ca requires tls_cert and tls_key");
+                }
+                return Ok(None);
+            }
+            (Some(_), None) => anyhow::bail!("tls_cert is set but tls_key is missing"),
+            (None, Some(_)) => anyhow::bail!("tls_key is set but tls_cert is missing"),
+        };
+        Ok(Some(TlsFiles {
+            cert: PathBuf::from(cert),
+            key: PathBuf::from(key),
+            client_ca: self.tls_client_ca.as_ref().map(PathBuf::from),
+        }))
+    }
+
+    /// 合并热重载的新配置：凭据、allow_new_nodes、max_running_tasks 和节点心跳配置立即生效，其他项保留当前值直到重启
+    /// 返回合并后的配置和变化的配置项；合并后的凭据配置无效时报错，当前配置不变
+    pub fn reload_from(&self, new: ServerConfig) -> anyhow::Result<(ServerConfig, ConfigChanges)> {
+        let mut changes = ConfigChanges::default();
+        let mut merged = self.clone();
+
+        if merged.token != new.token {
+            merged.token = new.token;
+            changes.applied.push("token");
+        }
+        if merged.admin_keys != new.admin_keys {
+            merged.admin_keys = new.admin_keys;
+            changes.applied.push("admin_keys");
+        }
+        if merged.node_keys != new.node_keys {
+            merged.node_keys = new.node_keys;
+            changes.applied.push("node_keys");
+        }
+        if merged.allow_new_nodes != new.allow_new_nodes {
+            merged.allow_new_nodes = new.allow_new_nodes;
+            changes.applied.push("allow_new_nodes");
+        }
+        if merged.max_running_tasks != new.max_running_tasks {
+            merged.max_running_tasks = new.max_running_tasks;
+            changes.applied.push("max_running_tasks");
+        }
+        if merged.node_timeout_secs != new.node_timeout_secs {
+            merged.node_timeout_secs = new.node_timeout_secs;
+            changes.applied.push("node_timeout_secs");
+        }
+        if merged.node_retention_secs != new.node_retention_secs {
+            merged.node_retention_secs = new.node_retention_secs;
+            changes.applied.push("node_retention_secs");
+        }
+        if merged.node_check_interval_secs != new.node_check_interval_secs {
+            merged.node_check_interval_secs = new.node_check_interval_secs;
+            changes.applied.push("node_check_interval_secs");
+        }
+
+        for (field, changed) in [
+            ("bind", self.bind != new.bind),
+            ("state_dir", self.state_dir != new.state_dir),
+            ("tls_cert", self.tls_cert != new.tls_cert),
+            ("tls_key", self.tls_key != new.tls_key),
+            ("tls_client_ca", self.tls_client_ca != new.tls_client_ca),
+            ("webhooks", self.webhooks != new.webhooks),
+        ] {
+            if changed {
+                changes.restart_required.push(field);
+            }
+        }
+
+        // 例如新配置删除了 node_keys 并依赖尚未生效的 tls_client_ca
+        merged.validate_credentials()?;
+        Ok((merged, changes))
+    }
+
+    /// 所有已配置的密钥（用于检查默认值）
+    pub fn all_secrets(&self) -> impl Iterator<Item = &str> {
+        self.token
+            .iter()
+            .chain(self.admin_keys.values())
+            .chain(self.node_keys.values())
+            .map(String::as_str)
+    }
+
+    /// 旧版 token 是否可以访问管理员接口：只有完全没有配置分组密钥时才可以
+    /// 配置了 admin_keys 或 node_keys 后，token 只作为节点凭据，避免持有 token 的 GridNode 调用管理员接口
+    pub fn legacy_token_is_admin(&self) -> bool {
+        self.admin_keys.is_empty() && self.node_keys.is_empty()
+    }
+
+    /// 检查默认密钥和旧版 token，有问题时记录警告（启动和重新加载配置时调用）
+    pub fn warn_insecure_keys(&self) {
+        if self.all_secrets().any(|secret| {
+            matches!(
+                secret,
+                "change-me-in-production"
+                    | "your-secret-token-change-this"
+                    | "your-admin-key-change-this"
+                    | "your-node-key-change-this"
+            )
+        }) {
+            tracing::warn!(
+                "WARNING: Using default key! Please change it in the config file for security."
+            );
+        }
+        if self.token.is_some() {
+            if self.legacy_token_is_admin() {
+                tracing::warn!(
+                    "Legacy 'token' grants both admin and node access; consider admin_keys/node_keys instead"
+                );
+            } else {
+                tracing::warn!(
+                    "Legacy 'token' is only accepted for node access because admin_keys/node_keys are configured"
+                );
+            }
+        }
+    }
+
     /// 保存配置到文件（保留供未来使用）
     #[allow(dead_code)]
     pub fn save_to_file<P: AsRef<Path>>(&self, path: P) -> anyhow::Result<()> {
@@ -45,11 +343,63 @@ impl ServerConfig {
 pub fn generate_default_config() -> String {
     r#"# IDM-GridCore ComputeHub Configuration
 
+# 修改后自动重新加载（也可以发送 SIGHUP）：凭据、max_running_tasks 和节点心跳配置立即生效，
+# bind、state_dir、TLS 文件路径和 webhooks 需要重启
+
 # 监听地址
 bind = "0.0.0.0:8080"
 
-# 节点认证 Token（必须修改，用于验证 GridNode）
-# GridNode 需要在配置中设置相同的 token 才能连接
-token = "your-secret-token-change-this"
+# 状态持久化目录（任务队列和节点注册表，重启后自动恢复）
+# 默认为配置文件所在目录下的 state/
+# state_dir = "/var/lib/idm-gridcore/state"
+
+# 同时运行的任务数上限（默认 1）
+# 大于 1 时，节点按任务的 weight / max_containers 公平分配给各运行中的任务
+# max_running_tasks = 1
+
+# 节点心跳：超过 node_timeout_secs 没有心跳的节点标记为 Offline（不再分配任务），
+# Offline 超过 node_retention_secs 后从节点列表中删除；每 node_check_interval_secs 检查一次
+# node_timeout_secs = 60
+# node_retention_secs = 86400
+# node_check_interval_secs = 30
+
+# TLS（同时设置证书和私钥时启用 HTTPS，否则为明文 HTTP）
+# 证书文件更新后自动重新加载，无需重启
+# tls_cert = "/etc/idm-gridcore/tls/cert.pem"
+# tls_key = "/etc/idm-gridcore/tls/key.pem"
+
+# 双向 TLS：签发 GridNode 客户端证书的 CA，设置后节点可以用客户端证书代替 node_keys 认证
+# 节点 ID 取自客户端证书主题的 CN
+# tls_client_ca = "/etc/idm-gridcore/tls/node-ca.pem"
+
+# 是否允许用共享节点密钥注册新的节点 ID（默认 true）
+# 设为 false 后只有已注册的节点能使用共享密钥，新节点需要客户端证书（或临时改回 true）
+# allow_new_nodes = true
+
+# 旧版单一 Token：同时作为管理员和节点凭据（不推荐，建议使用下面的分组密钥）
+# 配置了 admin_keys 或 node_keys 时只作为节点凭据
+# token = "your-secret-token-change-this"
+
+# 注意：其他顶层配置项必须写在 [admin_keys] / [node_keys] 之前
+
+# 管理员密钥（名称 = 密钥），用于 /api/* 接口
+# 每个使用者一个密钥，泄露时只需删除对应的一行
+[admin_keys]
+admin = "your-admin-key-change-this"
+
+# 节点密钥（名称 = 密钥），用于 /gridnode/* 接口
+# GridNode 配置中的 token 设置为其中一个密钥
+# 吊销节点后，被吊销的机器仍持有这里的密钥，可以用新的节点 ID 重新注册：
+# 应更换该密钥，或设置 allow_new_nodes = false（写在 [admin_keys] 之前）禁止用共享密钥注册新节点
+[node_keys]
+default = "your-node-key-change-this"
+
+# 事件 webhook（可配置多个），事件类型与 /api/events 相同，events 为空时发送全部事件
+# 设置 secret 后请求带 X-GridCore-Signature: sha256=<HMAC-SHA256(请求体)>
+# [[webhooks]]
+# url = "https://example.com/hooks/gridcore"
+# events = ["task_finished", "node_error"]
+# secret = "your-webhook-secret"
+# max_attempts = 5
 "#.to_string()
 }
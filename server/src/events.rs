@@ -0,0 +1,140 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use tokio::sync::broadcast;
+
+use crate::models::{OfflineReason, TaskStatus};
+
+/// 保留的最近事件数（客户端重连时据此补发）
+const REPLAY_BUFFER_SIZE: usize = 1000;
+
+/// 所有事件类型（与 Event 序列化后的 type 字段一致，用于校验 webhook 的事件过滤条件）
+pub const EVENT_TYPES: &[&str] = &[
+    "task_created",
+    "task_requeued",
+    "task_started",
+    "task_finished",
+    "task_deleted",
+    "node_registered",
+    "node_timed_out",
+    "node_recovered",
+    "node_purged",
+    "node_drain_requested",
+    "node_stop_requested",
+    "node_resumed",
+    "node_error",
+    "node_revoked",
+];
+
+/// 任务和节点生命周期事件
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// 注册了新任务
+    TaskCreated { task: String },
+    /// 已结束的任务重新排队
+    TaskRequeued { task: String },
+    /// 任务开始运行
+    TaskStarted { task: String },
+    /// 任务结束（Completed / Cancelled / Failed）
+    TaskFinished { task: String, status: TaskStatus },
+    /// 任务被删除（status 为删除前的状态）
+    TaskDeleted { task: String, status: TaskStatus },
+    /// 节点注册（包括重新注册）
+    NodeRegistered { node_id: String, hostname: String },
+    /// 节点心跳超时，已标记为 Offline
+    NodeTimedOut {
+        node_id: String,
+        reason: OfflineReason,
+    },
+    /// Offline 节点恢复心跳，重新上线
+    NodeRecovered { node_id: String },
+    /// Offline 节点超过保留时间，已移出节点列表
+    NodePurged { node_id: String },
+    /// 管理员请求节点排空（不再启动新容器）
+    NodeDrainRequested { node_id: String },
+    /// 管理员请求节点停止
+    NodeStopRequested { node_id: String },
+    /// 管理员取消排空或停止请求，节点恢复正常
+    NodeResumed { node_id: String },
+    /// 节点上报进入 Error 状态
+    NodeError {
+        node_id: String,
+        active_containers: u32,
+    },
+    /// 节点被吊销
+    NodeRevoked { node_id: String },
+}
+
+/// 带序号和时间的事件（序号用作 SSE 的 id，重连时通过 Last-Event-ID 补发）
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRecord {
+    pub id: u64,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// 事件总线：广播新事件，并保留最近的事件供重连的客户端补发
+/// 事件只保存在内存中，ComputeHub 重启后序号从 1 开始
+#[derive(Debug)]
+pub struct EventBus {
+    next_id: u64,
+    buffer: VecDeque<EventRecord>,
+    sender: broadcast::Sender<EventRecord>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(REPLAY_BUFFER_SIZE);
+        Self {
+            next_id: 1,
+            buffer: VecDeque::with_capacity(REPLAY_BUFFER_SIZE),
+            sender,
+        }
+    }
+
+    /// 发布事件
+    pub fn publish(&mut self, event: Event) {
+        let record = EventRecord {
+            id: self.next_id,
+            at: Utc::now(),
+            event,
+        };
+        self.next_id += 1;
+
+        if self.buffer.len() == REPLAY_BUFFER_SIZE {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(record.clone());
+        // 没有订阅者时发送失败，可以忽略
+        let _ = self.sender.send(record);
+    }
+
+    /// 订阅事件，同时返回需要补发的事件（序号大于 last_id 的缓存事件）
+    /// last_id 为 None 时不补发；last_id 不小于下一个序号（ComputeHub 重启过）时补发全部缓存
+    /// 调用方持有 AppStateInner 的锁，补发的事件和之后广播的事件之间不会有遗漏或重复
+    pub fn subscribe(
+        &self,
+        last_id: Option<u64>,
+    ) -> (Vec<EventRecord>, broadcast::Receiver<EventRecord>) {
+        let backlog = match last_id {
+            None => Vec::new(),
+            Some(last_id) => {
+                let last_id = if last_id >= self.next_id { 0 } else { last_id };
+                self.buffer
+                    .iter()
+                    .filter(|record| record.id > last_id)
+                    .cloned()
+                    .collect()
+            }
+        };
+        (backlog, self.sender.subscribe())
+    }
+}
@@ -1,31 +1,53 @@
+mod audit;
 mod auth;
+mod autofinish;
 mod config;
+mod events;
+mod metrics;
 mod models;
+mod reload;
+mod store;
+mod tls;
+mod webhooks;
 
 use axum::{
-    extract::State,
-    http::StatusCode,
+    body::Bytes,
+    extract::{rejection::JsonRejection, Extension, FromRequest, Request, State},
+    http::{header, HeaderMap, StatusCode},
     middleware,
-    response::Json,
-    routing::{get, post},
+    response::{
+        sse::{Event as SseEvent, KeepAlive, Sse},
+        IntoResponse, Json, Response,
+    },
+    routing::{get, patch, post},
     Router,
 };
+use auth::{AdminKey, NodeAuth};
 use config::{generate_default_config, ServerConfig};
 use models::{
-    AppState, AppStateInner, CreateTaskRequest, HeartbeatRequest, HeartbeatResponse, Node,
-    NodeStatus, RegisterNodeRequest, RegisterNodeResponse, Task, TaskConfig, TaskListResponse,
-    TaskStatus,
+    AppState, AppStateInner, CreateTaskRequest, EndTaskRequest, ErrorResponse, HeartbeatRequest,
+    HeartbeatResponse, Node, NodeDetailResponse, NodeMode, NodeStatus, RegisterNodeRequest,
+    RegisterNodeResponse, RepositionTaskRequest, SetParallelismRequest, Task, TaskConfig,
+    TaskDetailResponse, TaskError, TaskListResponse, TaskStatus, UpdateTaskRequest, default_revision,
 };
 use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::RwLock;
-use tokio::time::{interval, Duration};
+use futures::stream::{self, Stream, StreamExt};
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use store::StateStore;
+use tokio::sync::{broadcast, RwLock};
+use tokio::time::{sleep, Duration};
 use tracing::{info, warn};
 
 const CONFIG_FILENAME: &str = "computehub.toml";
 const CONFIG_DIR_NAME: &str = "idm-gridcore";
+/// 节点长轮询任务变化的最长等待时间（秒）
+const MAX_TASK_WAIT_SECS: u64 = 60;
+/// 审计日志查询默认返回的最大条数
+const DEFAULT_AUDIT_LIMIT: usize = 1000;
 
 /// 获取配置文件路径（按优先级）
 /// 1. 环境变量 IDM_GRIDCORE_CONFIG
@@ -84,7 +106,7 @@ async fn main() -> anyhow::Result<()> {
         let default_config = generate_default_config();
         std::fs::write(&config_path, default_config)?;
         info!("Created default config at {}", config_path.display());
-        info!("Please edit the config file and set a secure token, then restart");
+        info!("Please edit the config file and set secure admin/node keys, then restart");
         return Ok(());
     }
 
@@ -93,66 +115,141 @@ async fn main() -> anyhow::Result<()> {
     info!("Loaded config from {}", config_path.display());
     info!("Bind address: {}", server_config.bind);
 
-    // 检查默认 token
-    if server_config.token == "change-me-in-production"
-        || server_config.token == "your-secret-token-change-this"
-    {
-        warn!("WARNING: Using default token! Please change it in the config file for security.");
-    }
+    // 检查默认密钥
+    server_config.warn_insecure_keys();
 
-    // 初始化状态
-    let state: AppState = Arc::new(RwLock::new(AppStateInner::new(server_config.clone())));
+    // 初始化状态，并从持久化目录恢复任务队列和节点注册表
+    let state_dir = server_config
+        .state_dir
+        .as_ref()
+        .map(PathBuf::from)
+        .unwrap_or_else(|| {
+            config_path
+                .parent()
+                .map(|dir| dir.join("state"))
+                .unwrap_or_else(|| PathBuf::from("state"))
+        });
+    let mut inner = AppStateInner::new(server_config.clone());
+    inner.restore(StateStore::open(&state_dir)?)?;
+    inner.set_audit_log(audit::AuditLog::open(&state_dir)?);
+    let state: AppState = Arc::new(RwLock::new(inner));
 
-    // 启动节点清理任务
-    let cleanup_state = state.clone();
+    // 启动节点心跳检查任务（超时标记为 Offline，超过保留时间后删除）
+    let liveness_state = state.clone();
     tokio::spawn(async move {
-        let mut interval = interval(Duration::from_secs(30));
         loop {
-            interval.tick().await;
-            let mut state = cleanup_state.write().await;
-            let before = state.nodes.len();
-            state.cleanup_offline_nodes(60); // 60秒超时
-            let after = state.nodes.len();
-            if before != after {
-                info!("Cleaned up {} offline nodes", before - after);
-            }
+            // 每次重新读取间隔，热重载后立即生效
+            let check_interval = liveness_state.read().await.config.node_check_interval_secs;
+            sleep(Duration::from_secs(check_interval)).await;
+            liveness_state.write().await.check_node_liveness();
         }
     });
 
+    // 启动自动完成检查任务（仅对设置了 auto_finish 的任务生效）
+    autofinish::spawn_auto_finish(state.clone());
+
+    // 监听配置文件变化和 SIGHUP，热重载凭据等配置
+    reload::spawn_reload(state.clone(), config_path.clone())?;
+
+    // 启动 webhook 投递
+    webhooks::spawn_webhooks(state.clone()).await?;
+
     // 构建路由
-    let protected_routes = Router::new()
+    // 管理员接口和节点接口使用不同的凭据
+    let admin_routes = Router::new()
         .route("/api/tasks", post(create_task).get(list_tasks))
         .route("/api/tasks/next", post(next_task))
         .route("/api/tasks/finish", post(finish_task))
+        .route("/api/tasks/cancel", post(cancel_task))
+        .route("/api/tasks/fail", post(fail_task))
+        .route(
+            "/api/tasks/{name}",
+            get(get_task).patch(update_task).delete(delete_task),
+        )
+        .route("/api/tasks/{name}/requeue", post(requeue_task))
+        .route("/api/tasks/{name}/position", patch(reposition_task))
         .route("/api/nodes", get(list_nodes))
+        .route("/api/nodes/{node_id}", get(get_node))
+        .route("/api/events", get(event_stream))
+        .route("/api/audit", get(list_audit))
         .route("/api/nodes/{node_id}/stop", post(stop_node))
+        .route("/api/nodes/{node_id}/drain", post(drain_node))
+        .route("/api/nodes/{node_id}/resume", post(resume_node))
+        .route("/api/nodes/{node_id}/parallelism", patch(set_node_parallelism))
+        .route("/api/nodes/{node_id}/revoke", post(revoke_node))
+        .route("/metrics", get(metrics))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::admin_auth_middleware,
+        ));
+
+    let node_routes = Router::new()
         .route("/gridnode/register", post(register_node))
         .route("/gridnode/heartbeat", post(heartbeat))
         .route("/gridnode/task", get(get_current_task))
-        .route_layer(middleware::from_fn_with_state(state.clone(), auth::auth_middleware));
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::node_auth_middleware,
+        ));
 
     let app = Router::new()
         .route("/health", get(auth::health_check))
-        .merge(protected_routes)
+        .merge(admin_routes)
+        .merge(node_routes)
         .with_state(state);
 
     // 解析绑定地址
     let addr: SocketAddr = server_config.bind.parse()?;
     info!("IDM-GridCore ComputeHub listening on {}", addr);
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    match server_config.tls_files()? {
+        Some(files) => {
+            let tls_config = tls::load(&files)?;
+            if files.client_ca.is_some() {
+                info!("TLS enabled (client certificates accepted for GridNodes)");
+            } else {
+                info!("TLS enabled");
+            }
+            tls::spawn_reload(tls_config.clone(), files);
+            axum_server::bind(addr)
+                .acceptor(tls::NodeCertAcceptor::new(tls_config))
+                .serve(app.into_make_service())
+                .await?;
+        }
+        None => {
+            if !addr.ip().is_loopback() {
+                warn!("TLS is not configured; tokens are sent in clear text (set tls_cert/tls_key)");
+            }
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            axum::serve(listener, app).await?;
+        }
+    }
 
     Ok(())
 }
 
 // ========== 用户 API ==========
 
+/// Prometheus 指标（文本格式，需要管理员密钥）
+async fn metrics(
+    State(state): State<AppState>,
+) -> Result<([(header::HeaderName, &'static str); 1], String), (StatusCode, String)> {
+    let state = state.read().await;
+    let body = state.metrics.render(&state).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to render metrics: {}", e),
+        )
+    })?;
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body))
+}
+
 /// 注册新任务
 async fn create_task(
     State(state): State<AppState>,
-    Json(req): Json<CreateTaskRequest>,
-) -> Result<StatusCode, (StatusCode, String)> {
+    Extension(admin): Extension<AdminKey>,
+    TaskJson(req): TaskJson<CreateTaskRequest>,
+) -> Result<StatusCode, (StatusCode, Json<ErrorResponse>)> {
     let name = req.name.clone();
     let task = Task {
         name: req.name,
@@ -162,45 +259,188 @@ async fn create_task(
         output_redis: req.output_redis,
         input_queue: req.input_queue,
         output_queue: req.output_queue,
+        priority: req.priority,
+        weight: req.weight,
+        max_containers: req.max_containers,
+        auto_finish: req.auto_finish,
+        resources: req.resources,
+        env: req.env,
+        command: req.command,
+        entrypoint: req.entrypoint,
+        node_selector: req.node_selector,
+        revision: default_revision(),
     };
+    validate_task(&task)?;
 
     let mut state = state.write().await;
+    // 节点通过 task_name 判断任务是否切换，任务名在整个队列中必须唯一
+    if state.has_task(&name) {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(ErrorResponse {
+                error: "duplicate_task",
+                message: format!("Task '{}' already exists", name),
+                fields: Vec::new(),
+            }),
+        ));
+    }
     state.add_task(task);
+    let after = state.task_state(&name);
+    state.record_audit(&admin.0, "create_task", &name, None, after);
 
     info!("Task '{}' registered", name);
     Ok(StatusCode::CREATED)
 }
 
+/// 更新任务定义（Pending/Running）
+/// 镜像、Redis 或队列变化时递增修订号，节点会重启该任务的容器
+async fn update_task(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    TaskJson(req): TaskJson<UpdateTaskRequest>,
+) -> Result<Json<TaskDetailResponse>, (StatusCode, Json<ErrorResponse>)> {
+    let mut state = state.write().await;
+
+    let existing = state
+        .tasks
+        .iter()
+        .find(|entry| entry.task.name == name)
+        .map(|entry| entry.task.clone())
+        .ok_or_else(|| task_error_json(TaskError::NotFound(name.clone())))?;
+    let task = req.apply_to(&existing);
+    validate_task(&task)?;
+
+    let before = state.task_state(&name);
+    let revision = state.update_task(&name, task).map_err(task_error_json)?;
+    let after = state.task_state(&name);
+    state.record_audit(&admin.0, "update_task", &name, before, after);
+    if revision != existing.revision {
+        info!("Task '{}' updated to revision {}", name, revision);
+    } else {
+        info!("Task '{}' updated (revision unchanged)", name);
+    }
+
+    state.task_detail(&name).map(Json).map_err(task_error_json)
+}
+
+/// 任务接口的 JSON 请求体：解析失败时返回 ErrorResponse（invalid_request），而不是纯文本
+struct TaskJson<T>(T);
+
+impl<T, S> FromRequest<S> for TaskJson<T>
+where
+    Json<T>: FromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = (StatusCode, Json<ErrorResponse>);
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        match Json::<T>::from_request(req, state).await {
+            Ok(Json(value)) => Ok(Self(value)),
+            Err(rejection) => Err((
+                rejection.status(),
+                Json(ErrorResponse {
+                    error: "invalid_request",
+                    message: rejection.body_text(),
+                    fields: Vec::new(),
+                }),
+            )),
+        }
+    }
+}
+
+/// 校验任务定义，不合法时返回 422
+fn validate_task(task: &Task) -> Result<(), (StatusCode, Json<ErrorResponse>)> {
+    let fields = task.validate();
+    if fields.is_empty() {
+        return Ok(());
+    }
+    Err((
+        StatusCode::UNPROCESSABLE_ENTITY,
+        Json(ErrorResponse {
+            error: "invalid_task",
+            message: format!("Task '{}' has an invalid definition", task.name),
+            fields,
+        }),
+    ))
+}
+
 /// 列出所有任务
 async fn list_tasks(State(state): State<AppState>) -> Json<TaskListResponse> {
     let state = state.read().await;
 
-    let mut current = None;
+    let mut running = Vec::new();
     let mut pending = Vec::new();
     let mut completed = Vec::new();
+    let mut cancelled = Vec::new();
+    let mut failed = Vec::new();
 
-    for (_idx, (task, status)) in state.tasks.iter().enumerate() {
-        match status {
-            TaskStatus::Running => current = Some(task.name.clone()),
-            TaskStatus::Pending => pending.push(task.name.clone()),
-            TaskStatus::Completed => completed.push(task.name.clone()),
+    for entry in state.tasks.iter() {
+        let name = entry.task.name.clone();
+        match entry.status {
+            TaskStatus::Running => running.push(name),
+            TaskStatus::Pending => {}
+            TaskStatus::Completed => completed.push(name),
+            TaskStatus::Cancelled => cancelled.push(name),
+            TaskStatus::Failed => failed.push(name),
         }
     }
 
+    for idx in state.pending_order() {
+        pending.push(state.tasks[idx].task.name.clone());
+    }
+
     Json(TaskListResponse {
-        current,
+        current: state.get_current_task().map(|task| task.name.clone()),
+        running,
         pending,
         completed,
+        cancelled,
+        failed,
     })
 }
 
+/// 查看任务详情
+async fn get_task(
+    State(state): State<AppState>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<Json<TaskDetailResponse>, (StatusCode, String)> {
+    let state = state.read().await;
+
+    state
+        .task_detail(&name)
+        .map(Json)
+        .map_err(task_error_response)
+}
+
 /// 切换到下一个任务
 async fn next_task(
     State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
     let mut state = state.write().await;
 
-    match state.next_task() {
+    let target = state.get_current_task().map(|task| task.name.clone());
+    let before = target.as_deref().and_then(|name| state.task_state(name));
+    // 排在最前的 Pending 任务即为将要开始的任务，先记下它操作前的状态
+    let next = state
+        .pending_order()
+        .first()
+        .map(|&idx| state.tasks[idx].task.name.clone());
+    let next_before = next.as_deref().and_then(|name| state.task_state(name));
+    let switched = state.next_task();
+    if let Some(target) = &target {
+        let after = state.task_state(target);
+        state.record_audit(&admin.0, "next_task", target, before, after);
+    }
+    // 新开始的任务同样记录（包括之前没有任务在运行的情况）
+    if let Some((_, current)) = &switched {
+        let before = next_before.filter(|_| next.as_ref() == Some(current));
+        let after = state.task_state(current);
+        state.record_audit(&admin.0, "next_task", current, before, after);
+    }
+
+    match switched {
         Some((prev, current)) => {
             info!("Switched from '{}' to '{}'", prev, current);
             Ok(Json(serde_json::json!({
@@ -216,13 +456,20 @@ async fn next_task(
 }
 
 /// 完成当前任务（finish API）
+/// 可选请求体 {"name": "..."} 指定要完成的 Running 任务，省略时为主任务
 async fn finish_task(
     State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    body: Bytes,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let name = parse_end_task_request(&body)?;
     let mut state = state.write().await;
 
-    match state.finish_current_task() {
+    let before = end_task_target_state(&state, name.as_deref());
+    match state.finish_current_task(name.as_deref()) {
         Ok((completed, started)) => {
+            let after = state.task_state(&completed);
+            state.record_audit(&admin.0, "finish_task", &completed, before, after);
             if let Some(new_task) = &started {
                 info!("Finished '{}', started '{}'", completed, new_task);
                 Ok(Json(serde_json::json!({
@@ -239,39 +486,216 @@ async fn finish_task(
                 })))
             }
         }
-        Err(e) => Err((
-            StatusCode::BAD_REQUEST,
-            e.to_string(),
-        )),
+        Err(e) => Err(task_error_response(e)),
     }
 }
 
-/// 列出在线节点
+/// 取消当前任务（任务记为 Cancelled，自动开始下一个）
+async fn cancel_task(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    body: Bytes,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let name = parse_end_task_request(&body)?;
+    let mut state = state.write().await;
+
+    let before = end_task_target_state(&state, name.as_deref());
+    let (cancelled, started) = state
+        .cancel_current_task(name.as_deref())
+        .map_err(task_error_response)?;
+    let after = state.task_state(&cancelled);
+    state.record_audit(&admin.0, "cancel_task", &cancelled, before, after);
+    info!("Cancelled '{}', started {:?}", cancelled, started);
+    Ok(Json(serde_json::json!({
+        "cancelled": cancelled,
+        "started": started,
+    })))
+}
+
+/// 将当前任务标记为失败（任务记为 Failed，自动开始下一个）
+async fn fail_task(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    body: Bytes,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let name = parse_end_task_request(&body)?;
+    let mut state = state.write().await;
+
+    let before = end_task_target_state(&state, name.as_deref());
+    let (failed, started) = state
+        .fail_current_task(name.as_deref())
+        .map_err(task_error_response)?;
+    let after = state.task_state(&failed);
+    state.record_audit(&admin.0, "fail_task", &failed, before, after);
+    info!("Marked '{}' as failed, started {:?}", failed, started);
+    Ok(Json(serde_json::json!({
+        "failed": failed,
+        "started": started,
+    })))
+}
+
+/// 删除未在运行的任务
+async fn delete_task(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut state = state.write().await;
+
+    let before = state.task_state(&name);
+    state.delete_task(&name).map_err(task_error_response)?;
+    state.record_audit(&admin.0, "delete_task", &name, before, None);
+    info!("Task '{}' deleted", name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// 重新排队已结束的任务
+async fn requeue_task(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let mut state = state.write().await;
+
+    let before = state.task_state(&name);
+    let started = state.requeue_task(&name).map_err(task_error_response)?;
+    let after = state.task_state(&name);
+    state.record_audit(&admin.0, "requeue_task", &name, before, after);
+    info!("Task '{}' requeued (started: {})", name, started);
+    Ok(Json(serde_json::json!({
+        "requeued": name,
+        "started": started,
+    })))
+}
+
+/// 调整 Pending 任务的优先级和/或队列位置
+async fn reposition_task(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    axum::extract::Path(name): axum::extract::Path<String>,
+    Json(req): Json<RepositionTaskRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let mut state = state.write().await;
+
+    let before = state.task_state(&name);
+    state
+        .reposition_task(&name, req.priority, req.position)
+        .map_err(task_error_response)?;
+    let after = state.task_state(&name);
+    state.record_audit(&admin.0, "reposition_task", &name, before, after);
+    info!(
+        "Task '{}' repositioned (priority: {:?}, position: {:?})",
+        name, req.priority, req.position
+    );
+
+    let pending: Vec<String> = state
+        .pending_order()
+        .into_iter()
+        .map(|idx| state.tasks[idx].task.name.clone())
+        .collect();
+    // 实际位置（调度顺序中的索引，position 可能被优先级限制）
+    let position = pending.iter().position(|pending_name| *pending_name == name);
+    Ok(Json(serde_json::json!({
+        "task": name,
+        "position": position,
+        "pending": pending,
+    })))
+}
+
+/// 解析 finish/cancel/fail 的可选请求体 {"name": "..."}，空请求体表示主任务
+fn parse_end_task_request(body: &[u8]) -> Result<Option<String>, (StatusCode, String)> {
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+    serde_json::from_slice::<EndTaskRequest>(body)
+        .map(|req| req.name)
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid request body: {}", e)))
+}
+
+/// finish/cancel/fail 要结束的任务在操作前的状态（用于审计记录）
+fn end_task_target_state(state: &AppStateInner, name: Option<&str>) -> Option<serde_json::Value> {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => state.get_current_task()?.name.clone(),
+    };
+    state.task_state(&name)
+}
+
+/// 任务操作错误转换为 HTTP 响应
+fn task_error_response(e: TaskError) -> (StatusCode, String) {
+    let status = match e {
+        TaskError::NoRunningTask => StatusCode::BAD_REQUEST,
+        TaskError::NotFound(_) => StatusCode::NOT_FOUND,
+        TaskError::InvalidStatus { .. } => StatusCode::CONFLICT,
+    };
+    (status, e.to_string())
+}
+
+/// 将任务操作错误转换为结构化 JSON 响应（用于返回 ErrorResponse 的接口）
+fn task_error_json(e: TaskError) -> (StatusCode, Json<ErrorResponse>) {
+    let error = match e {
+        TaskError::NoRunningTask => "no_running_task",
+        TaskError::NotFound(_) => "task_not_found",
+        TaskError::InvalidStatus { .. } => "invalid_status",
+    };
+    let (status, message) = task_error_response(e);
+    (
+        status,
+        Json(ErrorResponse {
+            error,
+            message,
+            fields: Vec::new(),
+        }),
+    )
+}
+
+/// 列出节点（包括尚未超过保留时间的 Offline 节点）
 async fn list_nodes(State(state): State<AppState>) -> Json<Vec<models::Node>> {
     let state = state.read().await;
     let nodes: Vec<_> = state.nodes.values().cloned().collect();
     Json(nodes)
 }
 
+/// 查看节点详情（包括各工作实例的容器状态）
+async fn get_node(
+    State(state): State<AppState>,
+    axum::extract::Path(node_id): axum::extract::Path<String>,
+) -> Result<Json<NodeDetailResponse>, (StatusCode, String)> {
+    let state = state.read().await;
+
+    let node = state
+        .nodes
+        .get(&node_id)
+        .ok_or_else(|| (StatusCode::NOT_FOUND, format!("Node {} not found", node_id)))?;
+    let mut instances = node.instances.clone();
+    instances.sort_by_key(|instance| instance.instance_id);
+
+    Ok(Json(NodeDetailResponse {
+        node: node.clone(),
+        instances,
+    }))
+}
+
 // ========== 计算节点 API ==========
 
 /// 节点注册
+/// 新节点使用共享的节点密钥注册；已持有专属凭据的节点必须使用专属凭据重新注册
+/// request_credential 为 true 时签发（或轮换）该节点的专属凭据
 async fn register_node(
     State(state): State<AppState>,
+    Extension(auth): Extension<NodeAuth>,
     Json(req): Json<RegisterNodeRequest>,
-) -> Json<RegisterNodeResponse> {
-    let node_id = req
-        .node_id
-        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
-
-    // 根据架构确定平台（在移动前使用）
-    let platform = match req.architecture.as_str() {
-        "x86_64" => "linux/amd64",
-        "aarch64" => "linux/arm64",
-        "arm" => "linux/arm/v7",
-        _ => "linux/amd64", // 默认
+) -> Result<Json<RegisterNodeResponse>, StatusCode> {
+    let node_id = match (&auth, req.node_id) {
+        (_, Some(node_id)) => node_id,
+        // 客户端证书认证的节点使用证书中的身份
+        (NodeAuth::Certificate(cert_id), None) => cert_id.clone(),
+        (_, None) => uuid::Uuid::new_v4().to_string(),
     };
 
+    let mut state = state.write().await;
+    auth::authorize_node(&state, &auth, &node_id)?;
+
     let node = Node {
         id: node_id.clone(),
         hostname: req.hostname,
@@ -281,97 +705,344 @@ async fn register_node(
         status: NodeStatus::Online,
         runtime_status: None,
         active_containers: 0,
-        stop_requested: false,
+        mode: NodeMode::Active,
+        parallelism: None,
+        assigned_task: None,
+        assigned_instances: None,
+        labels: req.labels,
+        offline_since: None,
+        offline_reason: None,
+        registered_by_certificate: matches!(auth, NodeAuth::Certificate(_)),
+        instances: Vec::new(),
     };
 
-    let mut state = state.write().await;
-    state.register_node(node);
-
-    // 返回当前任务配置（根据节点架构选择镜像）
-    let current_task = state.get_current_task().and_then(|task| {
-        let image = task.get_image_for_platform(platform)?;
-        Some(TaskConfig {
-            task_name: task.name.clone(),
-            image,
-            redis_url: None,
-            input_redis: task.input_redis.clone(),
-            output_redis: task.output_redis.clone(),
-            input_queue: task.input_queue.clone(),
-            output_queue: task.output_queue.clone(),
-        })
-    });
+    // 使用客户端证书的节点不需要专属凭据
+    let node_secret = (req.request_credential && !matches!(auth, NodeAuth::Certificate(_)))
+        .then(auth::generate_node_secret);
+    let credential_hash = node_secret.as_deref().map(auth::hash_secret);
+    if !state.register_node(node, credential_hash) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // 返回分配给该节点的任务配置（根据节点架构选择镜像）
+    let current_task = state.task_config_for_node(&node_id);
+    let parallelism = state.nodes.get(&node_id).and_then(|node| node.parallelism);
 
     info!(
-        "Node '{}' registered with {} CPUs",
-        node_id, req.cpu_count
+        "Node '{}' registered with {} CPUs{}",
+        node_id,
+        req.cpu_count,
+        if node_secret.is_some() { " (credential issued)" } else { "" }
     );
 
-    Json(RegisterNodeResponse {
+    Ok(Json(RegisterNodeResponse {
         node_id,
         current_task,
-    })
+        node_secret,
+        parallelism,
+    }))
 }
 
 /// 节点心跳
 async fn heartbeat(
     State(state): State<AppState>,
+    Extension(auth): Extension<NodeAuth>,
     Json(req): Json<HeartbeatRequest>,
-) -> Json<HeartbeatResponse> {
+) -> Result<Json<HeartbeatResponse>, StatusCode> {
     let mut state = state.write().await;
+    auth::authorize_node(&state, &auth, &req.node_id)?;
 
-    // 先获取 stop_requested 值
-    let stop_requested = state
+    // 先获取管理员设置的模式和并行度
+    let (mode, parallelism) = state
         .nodes
         .get(&req.node_id)
-        .map(|node| node.stop_requested)
-        .unwrap_or(false);
+        .map(|node| (node.mode, node.parallelism))
+        .unwrap_or_default();
 
     // 然后更新心跳
     if state.nodes.contains_key(&req.node_id) {
-        state.update_heartbeat(&req.node_id, req.status, req.active_containers);
+        state.update_heartbeat(
+            &req.node_id,
+            req.status,
+            req.active_containers,
+            req.instances,
+        );
     }
 
-    Json(HeartbeatResponse { stop_requested })
+    Ok(Json(HeartbeatResponse {
+        stop_requested: mode == NodeMode::Stopping,
+        mode,
+        parallelism,
+    }))
 }
 
-/// 请求节点优雅停止
+/// 吊销节点：移出节点列表，该节点 ID（及其专属凭据）之后的请求返回 403，GridNode 收到后停止容器并退出
+/// 被吊销的机器仍持有共享的节点密钥，可以用新的节点 ID 重新注册：
+/// 需要更换 node_keys 中的密钥，或设置 allow_new_nodes = false
+async fn revoke_node(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    axum::extract::Path(node_id): axum::extract::Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let mut state = state.write().await;
+
+    let before = state.node_state(&node_id);
+    let existed = state.revoke_node(&node_id);
+    if existed {
+        state.record_audit(&admin.0, "revoke_node", &node_id, before, None);
+        warn!("Node {} revoked", node_id);
+        Ok(StatusCode::OK)
+    } else {
+        Err((StatusCode::NOT_FOUND, format!("Node {} not found", node_id)))
+    }
+}
+
+/// 任务和节点生命周期事件流（Server-Sent Events）
+/// 断线重连时 EventSource 自动带上 Last-Event-ID（也可以用查询参数 since 指定），补发之后缓存的事件
+async fn event_stream(
+    State(state): State<AppState>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<SseEvent, Infallible>>> {
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .or(params.get("since").map(String::as_str))
+        .and_then(|id| id.parse::<u64>().ok());
+    let (backlog, receiver) = state.read().await.events.subscribe(last_id);
+
+    // 客户端处理太慢、落后超过缓存大小时断开，由客户端带 Last-Event-ID 重连补发
+    let live = stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(record) => Some((record, receiver)),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!("Event stream client lagged behind by {} events, disconnecting", skipped);
+                None
+            }
+            Err(broadcast::error::RecvError::Closed) => None,
+        }
+    });
+
+    let events = stream::iter(backlog).chain(live).map(|record| {
+        let data = serde_json::to_string(&record).unwrap_or_default();
+        Ok(SseEvent::default().id(record.id.to_string()).data(data))
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// 查询审计日志
+/// 查询参数 since / until: RFC 3339 时间，返回 since <= at < until 的记录
+/// 查询参数 limit: 最多返回的条数（默认 DEFAULT_AUDIT_LIMIT），超出时返回范围内最近的记录
+async fn list_audit(
+    State(state): State<AppState>,
+    axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
+) -> Result<Json<Vec<audit::AuditRecord>>, (StatusCode, String)> {
+    let parse_time = |key: &str| {
+        params
+            .get(key)
+            .map(|value| {
+                chrono::DateTime::parse_from_rfc3339(value)
+                    .map(|time| time.with_timezone(&chrono::Utc))
+                    .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid {}: {}", key, e)))
+            })
+            .transpose()
+    };
+    let since = parse_time("since")?;
+    let until = parse_time("until")?;
+    let limit = match params.get("limit") {
+        Some(limit) => limit
+            .parse::<usize>()
+            .map_err(|e| (StatusCode::BAD_REQUEST, format!("Invalid limit: {}", e)))?,
+        None => DEFAULT_AUDIT_LIMIT,
+    };
+
+    // 只在锁内取路径，读取文件时不持有状态锁
+    let Some(path) = state
+        .read()
+        .await
+        .audit_log()
+        .map(|audit| audit.path().to_path_buf())
+    else {
+        return Ok(Json(Vec::new()));
+    };
+    let records = tokio::task::spawn_blocking(move || audit::query(&path, since, until, limit))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result)
+        .map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to read audit log: {}", e),
+            )
+        })?;
+    Ok(Json(records))
+}
+
+/// 请求节点优雅停止（停止所有容器后退出）
 async fn stop_node(
     State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    axum::extract::Path(node_id): axum::extract::Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    set_node_mode(state, admin, node_id, NodeMode::Stopping).await
+}
+
+/// 请求节点排空（不再启动新容器，运行中的容器自然结束）
+async fn drain_node(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    axum::extract::Path(node_id): axum::extract::Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    set_node_mode(state, admin, node_id, NodeMode::Draining).await
+}
+
+/// 取消排空或停止请求（节点收到停止请求之前有效）
+async fn resume_node(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
     axum::extract::Path(node_id): axum::extract::Path<String>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    set_node_mode(state, admin, node_id, NodeMode::Active).await
+}
+
+/// 设置节点并行度（节点在下次心跳时增减工作实例）
+/// 请求体 {"parallelism": N}，N 在 1 到节点注册的 cpu_count 之间；null 恢复节点本地配置
+async fn set_node_parallelism(
+    State(state): State<AppState>,
+    Extension(admin): Extension<AdminKey>,
+    axum::extract::Path(node_id): axum::extract::Path<String>,
+    Json(req): Json<SetParallelismRequest>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let mut state = state.write().await;
+
+    let Some(node) = state.nodes.get(&node_id) else {
+        return Err((StatusCode::NOT_FOUND, format!("Node {} not found", node_id)));
+    };
+    if let Some(parallelism) = req.parallelism
+        && !(1..=node.cpu_count).contains(&parallelism)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!(
+                "parallelism must be between 1 and {} (the node's configured parallelism)",
+                node.cpu_count
+            ),
+        ));
+    }
+
+    let before = state.node_state(&node_id);
+    state.set_node_parallelism(&node_id, req.parallelism);
+    let after = state.node_state(&node_id);
+    state.record_audit(&admin.0, "set_node_parallelism", &node_id, before, after);
+    info!("Node {} parallelism set to {:?}", node_id, req.parallelism);
+    Ok(Json(serde_json::json!({
+        "node_id": node_id,
+        "parallelism": req.parallelism,
+    })))
+}
+
+/// 设置节点模式并记录审计日志
+async fn set_node_mode(
+    state: AppState,
+    admin: AdminKey,
+    node_id: String,
+    mode: NodeMode,
 ) -> Result<StatusCode, (StatusCode, String)> {
     let mut state = state.write().await;
 
-    if let Some(node) = state.nodes.get_mut(&node_id) {
-        node.stop_requested = true;
-        info!("Stop requested for node {}", node_id);
+    let before = state.node_state(&node_id);
+    if state.set_node_mode(&node_id, mode) {
+        let after = state.node_state(&node_id);
+        let action = match mode {
+            NodeMode::Active => "resume_node",
+            NodeMode::Draining => "drain_node",
+            NodeMode::Stopping => "stop_node",
+        };
+        state.record_audit(&admin.0, action, &node_id, before, after);
+        info!("Node {} set to {}", node_id, mode.as_str());
         Ok(StatusCode::OK)
     } else {
         Err((StatusCode::NOT_FOUND, format!("Node {} not found", node_id)))
     }
 }
 
-/// 获取当前任务（非阻塞）
-/// 查询参数 platform: 如 linux/amd64, linux/arm64
+/// 获取当前任务
+/// 查询参数 node_id: 节点 ID，返回调度器分配给该节点的任务
+/// 查询参数 platform: 如 linux/amd64, linux/arm64（旧版节点未提供 node_id 时，返回主任务）
+/// 查询参数 wait: 长轮询秒数（最多 MAX_TASK_WAIT_SECS）。请求头 If-None-Match 与当前配置的 ETag 相同时，
+/// 等待分配变化后再返回；等待超时仍未变化返回 304。不带 wait 时立即返回（旧版节点的轮询方式）
 async fn get_current_task(
     State(state): State<AppState>,
+    Extension(auth): Extension<NodeAuth>,
     axum::extract::Query(params): axum::extract::Query<HashMap<String, String>>,
-) -> Json<Option<TaskConfig>> {
-    let state = state.read().await;
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let wait = params
+        .get("wait")
+        .and_then(|wait| wait.parse::<u64>().ok())
+        .unwrap_or(0)
+        .min(MAX_TASK_WAIT_SECS);
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(wait);
+    let known_etag = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
 
-    let platform: &str = params.get("platform").map(|s| s.as_str()).unwrap_or("linux/amd64");
+    loop {
+        // 在同一把读锁内计算配置并订阅，不会漏掉两者之间的变化
+        let (config, mut changes) = {
+            let state = state.read().await;
+            (
+                task_config_for_request(&state, &auth, &params)?,
+                state.subscribe_assignments(),
+            )
+        };
+        let etag = task_config_etag(&config);
+        let headers = [(header::ETAG, etag.clone())];
 
-    let config = state.get_current_task().and_then(|task| {
-        let image = task.get_image_for_platform(platform)?;
-        Some(TaskConfig {
-            task_name: task.name.clone(),
-            image,
-            redis_url: None,
-            input_redis: task.input_redis.clone(),
-            output_redis: task.output_redis.clone(),
-            input_queue: task.input_queue.clone(),
-            output_queue: task.output_queue.clone(),
-        })
-    });
+        if known_etag != Some(etag.as_str()) {
+            return Ok((headers, Json(config)).into_response());
+        }
+        if wait == 0 {
+            return Ok((StatusCode::NOT_MODIFIED, headers).into_response());
+        }
+        // 分配变化后重新计算（不一定影响本节点）；超时或 ComputeHub 退出时返回 304
+        match tokio::time::timeout_at(deadline, changes.changed()).await {
+            Ok(Ok(())) => continue,
+            _ => return Ok((StatusCode::NOT_MODIFIED, headers).into_response()),
+        }
+    }
+}
+
+/// 计算请求对应的任务配置（见 get_current_task）
+fn task_config_for_request(
+    state: &AppStateInner,
+    auth: &NodeAuth,
+    params: &HashMap<String, String>,
+) -> Result<Option<TaskConfig>, StatusCode> {
+    if let Some(node_id) = params.get("node_id") {
+        auth::authorize_node(state, auth, node_id)?;
+        if state.nodes.contains_key(node_id) {
+            return Ok(state.task_config_for_node(node_id));
+        }
+    } else if *auth != NodeAuth::Bootstrap {
+        // 专属凭据必须带上自己的 node_id
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // 未注册的节点没有标签，设置了 node_selector 的任务不下发
+    let platform: &str = params.get("platform").map(|s| s.as_str()).unwrap_or("linux/amd64");
+    Ok(state
+        .get_current_task()
+        .filter(|task| task.matches_labels(&HashMap::new()))
+        .and_then(|task| TaskConfig::for_platform(task, platform)))
+}
 
-    Json(config)
+/// 任务配置的 ETag（配置内容的 SHA-256 前 16 字节）
+/// 先转换为 serde_json::Value 使 env 等字段的键有序，重启后相同配置的 ETag 不变
+fn task_config_etag(config: &Option<TaskConfig>) -> String {
+    let body = serde_json::to_value(config)
+        .and_then(|value| serde_json::to_vec(&value))
+        .unwrap_or_default();
+    let digest = Sha256::digest(&body);
+    format!("\"{}\"", hex::encode(&digest[..16]))
 }
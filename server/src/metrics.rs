@@ -0,0 +1,193 @@
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::fmt;
+
+use crate::models::{AppStateInner, NodeStatus, TaskStatus};
+
+/// 心跳间隔的分桶（秒），默认心跳 30 秒、超时 60 秒
+const HEARTBEAT_AGE_BUCKETS: &[f64] = &[5.0, 10.0, 15.0, 30.0, 45.0, 60.0, 90.0, 120.0];
+
+/// 所有任务状态（导出时每个状态都输出一行，包括数量为 0 的状态）
+const TASK_STATUSES: [TaskStatus; 5] = [
+    TaskStatus::Pending,
+    TaskStatus::Running,
+    TaskStatus::Completed,
+    TaskStatus::Cancelled,
+    TaskStatus::Failed,
+];
+
+/// Prometheus 指标
+/// 计数器在事件发生时累加；节点和任务的当前状态在每次抓取时从 AppStateInner 计算
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// 任务状态变化次数（按新状态）
+    task_transitions: IntCounterVec,
+    /// 删除的任务数（按删除前的状态）
+    task_deletions: IntCounterVec,
+    /// 认证失败次数（按作用域和原因）
+    auth_failures: IntCounterVec,
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let task_transitions = IntCounterVec::new(
+            Opts::new(
+                "computehub_task_transitions_total",
+                "Task status transitions, by new status",
+            ),
+            &["status"],
+        )
+        .expect("valid metric");
+        let task_deletions = IntCounterVec::new(
+            Opts::new(
+                "computehub_tasks_deleted_total",
+                "Deleted tasks, by status before deletion",
+            ),
+            &["status"],
+        )
+        .expect("valid metric");
+        let auth_failures = IntCounterVec::new(
+            Opts::new(
+                "computehub_auth_failures_total",
+                "Rejected requests, by credential scope and reason",
+            ),
+            &["scope", "reason"],
+        )
+        .expect("valid metric");
+        registry
+            .register(Box::new(task_transitions.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(task_deletions.clone()))
+            .expect("unique metric");
+        registry
+            .register(Box::new(auth_failures.clone()))
+            .expect("unique metric");
+
+        Self {
+            registry,
+            task_transitions,
+            task_deletions,
+            auth_failures,
+        }
+    }
+
+    /// 记录一次任务状态变化
+    pub fn task_transition(&self, status: TaskStatus) {
+        self.task_transitions
+            .with_label_values(&[task_status_label(status)])
+            .inc();
+    }
+
+    /// 记录一次任务删除
+    pub fn task_deleted(&self, status: TaskStatus) {
+        self.task_deletions
+            .with_label_values(&[task_status_label(status)])
+            .inc();
+    }
+
+    /// 记录一次认证失败
+    /// reason: missing（未携带凭据）/ invalid（凭据无效）/ mismatch（凭据不能代表该节点）/ revoked（节点已吊销）
+    /// / new_node（不允许用共享密钥注册新节点）
+    pub fn auth_failure(&self, scope: &str, reason: &str) {
+        self.auth_failures.with_label_values(&[scope, reason]).inc();
+    }
+
+    /// 以 Prometheus 文本格式导出所有指标
+    pub fn render(&self, state: &AppStateInner) -> anyhow::Result<String> {
+        let mut families = self.registry.gather();
+        families.extend(snapshot_registry(state)?.gather());
+        families.sort_by(|a, b| a.name().cmp(b.name()));
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&families, &mut buffer)?;
+        Ok(String::from_utf8(buffer)?)
+    }
+}
+
+/// 从当前状态计算节点和任务指标（每次抓取时重新生成）
+fn snapshot_registry(state: &AppStateInner) -> anyhow::Result<Registry> {
+    let registry = Registry::new();
+
+    let nodes = IntGaugeVec::new(
+        Opts::new(
+            "computehub_nodes_online",
+            "Online nodes, by architecture and runtime status",
+        ),
+        &["architecture", "runtime_status"],
+    )?;
+    let active_containers = IntGauge::new(
+        "computehub_active_containers",
+        "Running containers reported by all online nodes",
+    )?;
+    let heartbeat_age = Histogram::with_opts(
+        HistogramOpts::new(
+            "computehub_node_heartbeat_age_seconds",
+            "Time since each online node's last heartbeat",
+        )
+        .buckets(HEARTBEAT_AGE_BUCKETS.to_vec()),
+    )?;
+    let tasks = IntGaugeVec::new(
+        Opts::new("computehub_tasks", "Tasks in the queue, by status"),
+        &["status"],
+    )?;
+
+    let now = chrono::Utc::now();
+    for node in state.nodes.values() {
+        if node.status != NodeStatus::Online {
+            continue;
+        }
+        let runtime_status = node
+            .runtime_status
+            .map_or("unknown", |status| status.as_str());
+        nodes
+            .with_label_values(&[node.architecture.as_str(), runtime_status])
+            .inc();
+        active_containers.add(i64::from(node.active_containers));
+        let age = (now - node.last_seen).num_milliseconds().max(0) as f64 / 1000.0;
+        heartbeat_age.observe(age);
+    }
+
+    for status in TASK_STATUSES {
+        let count = state
+            .tasks
+            .iter()
+            .filter(|entry| entry.status == status)
+            .count();
+        tasks
+            .with_label_values(&[task_status_label(status)])
+            .set(count as i64);
+    }
+
+    registry.register(Box::new(nodes))?;
+    registry.register(Box::new(active_containers))?;
+    registry.register(Box::new(heartbeat_age))?;
+    registry.register(Box::new(tasks))?;
+    Ok(registry)
+}
+
+fn task_status_label(status: TaskStatus) -> &'static str {
+    match status {
+        TaskStatus::Pending => "pending",
+        TaskStatus::Running => "running",
+        TaskStatus::Completed => "completed",
+        TaskStatus::Cancelled => "cancelled",
+        TaskStatus::Failed => "failed",
+    }
+}
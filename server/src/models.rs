@@ -1,8 +1,12 @@
-use crate::config::ServerConfig;
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use crate::audit::{AuditLog, AuditRecord};
+use crate::config::{ConfigChanges, ServerConfig};
+use crate::events::{Event, EventBus};
+use crate::metrics::Metrics;
+use crate::store::{JournalEntry, JournalRecord, Snapshot, StateStore};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
 use chrono::{DateTime, Utc};
 
 /// 任务定义（支持多架构镜像）
@@ -29,20 +33,269 @@ pub struct Task {
     pub input_queue: Option<String>,
     /// 可选：覆盖输出队列名
     pub output_queue: Option<String>,
+    /// 优先级（越大越先执行，默认 0；同优先级按队列顺序）
+    #[serde(default)]
+    pub priority: i32,
+    /// 多任务并行时的节点分配权重（默认 1）
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// 多任务并行时最多占用的容器数（整个集群，None 表示不限制）
+    #[serde(default)]
+    pub max_containers: Option<u32>,
+    /// 可选：输入队列清空后自动完成（需要 input_redis 和 input_queue）
+    #[serde(default)]
+    pub auto_finish: Option<AutoFinishPolicy>,
+    /// 可选：每个容器的资源需求（节点会按自身上限裁剪）
+    #[serde(default)]
+    pub resources: Option<TaskResources>,
+    /// 可选：额外的容器环境变量（不能覆盖内置变量，如 TASK_NAME）
+    #[serde(default)]
+    pub env: Option<HashMap<String, String>>,
+    /// 可选：覆盖镜像的 CMD
+    #[serde(default)]
+    pub command: Option<Vec<String>>,
+    /// 可选：覆盖镜像的 ENTRYPOINT
+    #[serde(default)]
+    pub entrypoint: Option<Vec<String>>,
+    /// 可选：节点选择器，只分配给 labels 包含所有这些键值对的节点
+    #[serde(default)]
+    pub node_selector: Option<HashMap<String, String>>,
+    /// 修订号：镜像或 Redis/队列配置被更新时递增，节点据此重启容器
+    #[serde(default = "default_revision")]
+    pub revision: u64,
+}
+
+pub fn default_weight() -> u32 {
+    1
+}
+
+pub fn default_revision() -> u64 {
+    1
+}
+
+/// GridNode 为每个容器设置的内置环境变量，任务 env 不能覆盖
+pub const RESERVED_ENV_VARS: &[&str] = &[
+    "TASK_NAME",
+    "NODE_ID",
+    "INSTANCE_ID",
+    "INPUT_REDIS_URL",
+    "OUTPUT_REDIS_URL",
+    "INPUT_QUEUE",
+    "OUTPUT_QUEUE",
+];
+
+/// resources.memory_mb / shm_size_mb 的上限（16 TiB），节点换算为字节时不会溢出
+pub const MAX_RESOURCE_MB: u64 = 16 * 1024 * 1024;
+
+/// resources.cpus 的下限（Docker 接受的最小 CPU 限制）
+pub const MIN_RESOURCE_CPUS: f64 = 0.01;
+
+/// 每个容器的资源需求
+/// 未设置的项使用节点默认值（1 核、节点配置的 container_memory）
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TaskResources {
+    /// CPU 核数（可以是小数，如 0.5）
+    #[serde(default)]
+    pub cpus: Option<f64>,
+    /// 内存限制（MB）
+    #[serde(default)]
+    pub memory_mb: Option<u64>,
+    /// 最大进程数
+    #[serde(default)]
+    pub pids_limit: Option<i64>,
+    /// /dev/shm 大小（MB）
+    #[serde(default)]
+    pub shm_size_mb: Option<u64>,
+}
+
+/// 自动完成策略
+/// ComputeHub 定期查询输入队列长度，队列持续为空超过 grace_period_secs，
+/// 且已有节点运行过该任务的容器、执行该任务的节点都没有活跃容器时，自动完成该任务
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutoFinishPolicy {
+    /// 队列为空后的等待时间（秒），默认 300
+    #[serde(default = "default_grace_period")]
+    pub grace_period_secs: u64,
+    /// 是否要求执行该任务的节点都没有活跃容器（默认 true）
+    /// 容器在队列为空时阻塞等待而不退出的任务（如 BLPOP 循环）需要设为 false
+    #[serde(default = "default_require_idle_nodes")]
+    pub require_idle_nodes: bool,
+}
+
+fn default_grace_period() -> u64 {
+    300
+}
+
+fn default_require_idle_nodes() -> bool {
+    true
 }
 
 impl Task {
     /// 获取指定平台的镜像
     pub fn get_image_for_platform(&self, platform: &str) -> Option<String> {
         // 首先尝试从 images 映射中获取
-        if let Some(ref images) = self.images {
-            if let Some(image) = images.get(platform) {
-                return Some(image.clone());
-            }
+        if let Some(image) = self.images.as_ref().and_then(|images| images.get(platform)) {
+            return Some(image.clone());
         }
         // 回退到默认 image
         self.image.clone()
     }
+
+    /// 节点标签是否满足该任务的 node_selector（未设置选择器时总是满足）
+    pub fn matches_labels(&self, labels: &HashMap<String, String>) -> bool {
+        self.node_selector.as_ref().is_none_or(|selector| {
+            selector
+                .iter()
+                .all(|(key, value)| labels.get(key) == Some(value))
+        })
+    }
+
+    /// 任务能否在该节点上运行：镜像支持节点平台，且节点标签满足 node_selector
+    pub fn can_run_on(&self, node: &Node) -> bool {
+        self.get_image_for_platform(node.platform()).is_some() && self.matches_labels(&node.labels)
+    }
+
+    /// 两个定义下发给节点的配置（镜像、Redis、队列）是否相同
+    /// 不同时需要递增 revision，让节点重启容器
+    pub fn same_node_config(&self, other: &Task) -> bool {
+        self.image == other.image
+            && self.images == other.images
+            && self.input_redis == other.input_redis
+            && self.output_redis == other.output_redis
+            && self.input_queue == other.input_queue
+            && self.output_queue == other.output_queue
+            && self.resources == other.resources
+            && self.env == other.env
+            && self.command == other.command
+            && self.entrypoint == other.entrypoint
+    }
+
+    /// 校验任务定义，返回所有不合法的字段（为空表示通过）
+    /// 任务名是否重复需要结合当前队列判断，不在这里检查
+    pub fn validate(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+
+        if self.name.trim().is_empty() {
+            errors.push(FieldError::new("name", "must not be empty"));
+        } else if self.name.contains('/') || self.name.chars().any(char::is_control) {
+            errors.push(FieldError::new(
+                "name",
+                "must not contain '/' or control characters",
+            ));
+        }
+
+        let has_images = self.images.as_ref().is_some_and(|images| !images.is_empty());
+        match &self.image {
+            Some(image) if image.trim().is_empty() => {
+                errors.push(FieldError::new("image", "must not be empty"));
+            }
+            None if !has_images => {
+                errors.push(FieldError::new("image", "either image or images is required"));
+            }
+            _ => {}
+        }
+        if let Some(images) = &self.images {
+            for (platform, image) in images {
+                if image.trim().is_empty() {
+                    errors.push(FieldError::new(
+                        format!("images.{}", platform),
+                        "must not be empty",
+                    ));
+                }
+            }
+        }
+
+        for (field, url) in [
+            ("input_redis", &self.input_redis),
+            ("output_redis", &self.output_redis),
+        ] {
+            if let Some(url) = url
+                && let Err(e) = redis::IntoConnectionInfo::into_connection_info(url.as_str())
+            {
+                errors.push(FieldError::new(field, format!("invalid Redis URL: {}", e)));
+            }
+        }
+
+        if self.weight == 0 {
+            errors.push(FieldError::new("weight", "must be at least 1"));
+        }
+        if self.max_containers == Some(0) {
+            errors.push(FieldError::new("max_containers", "must be at least 1"));
+        }
+
+        if let Some(resources) = &self.resources {
+            if resources
+                .cpus
+                .is_some_and(|cpus| !cpus.is_finite() || cpus < MIN_RESOURCE_CPUS)
+            {
+                errors.push(FieldError::new(
+                    "resources.cpus",
+                    format!("must be at least {}", MIN_RESOURCE_CPUS),
+                ));
+            }
+            // Docker 要求内存限制至少 6MB
+            if resources.memory_mb.is_some_and(|memory| memory < 6) {
+                errors.push(FieldError::new("resources.memory_mb", "must be at least 6"));
+            } else if resources.memory_mb.is_some_and(|memory| memory > MAX_RESOURCE_MB) {
+                errors.push(FieldError::new(
+                    "resources.memory_mb",
+                    format!("must be at most {}", MAX_RESOURCE_MB),
+                ));
+            }
+            if resources.pids_limit.is_some_and(|pids| pids < 1) {
+                errors.push(FieldError::new("resources.pids_limit", "must be at least 1"));
+            }
+            if resources.shm_size_mb == Some(0) {
+                errors.push(FieldError::new("resources.shm_size_mb", "must be at least 1"));
+            } else if resources.shm_size_mb.is_some_and(|shm| shm > MAX_RESOURCE_MB) {
+                errors.push(FieldError::new(
+                    "resources.shm_size_mb",
+                    format!("must be at most {}", MAX_RESOURCE_MB),
+                ));
+            }
+        }
+
+        if let Some(env) = &self.env {
+            for key in env.keys() {
+                if key.is_empty() || key.contains('=') || key.contains('\0') {
+                    errors.push(FieldError::new(
+                        format!("env.{}", key),
+                        "invalid environment variable name",
+                    ));
+                } else if RESERVED_ENV_VARS.contains(&key.as_str()) {
+                    errors.push(FieldError::new(
+                        format!("env.{}", key),
+                        "is set by GridNode and cannot be overridden",
+                    ));
+                }
+            }
+        }
+        if let Some(selector) = &self.node_selector
+            && selector.keys().any(|key| key.trim().is_empty())
+        {
+            errors.push(FieldError::new("node_selector", "label keys must not be empty"));
+        }
+        // 空列表会让 Docker 清空镜像的 CMD/ENTRYPOINT，恢复镜像默认值应使用 null（None）
+        for (field, args) in [("command", &self.command), ("entrypoint", &self.entrypoint)] {
+            if args.as_ref().is_some_and(|args| args.is_empty()) {
+                errors.push(FieldError::new(
+                    field,
+                    "must not be empty (use null to restore the image default)",
+                ));
+            }
+        }
+
+        if self.auto_finish.is_some() {
+            if self.input_redis.is_none() {
+                errors.push(FieldError::new("input_redis", "required by auto_finish"));
+            }
+            if self.input_queue.is_none() {
+                errors.push(FieldError::new("input_queue", "required by auto_finish"));
+            }
+        }
+
+        errors
+    }
 }
 
 /// 任务状态（内部使用）
@@ -51,15 +304,61 @@ pub enum TaskStatus {
     Pending,
     Running,
     Completed,
+    /// 被管理员取消
+    Cancelled,
+    /// 被标记为失败
+    Failed,
+}
+
+impl TaskStatus {
+    /// 是否为终止状态（可以重新排队）
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Completed | TaskStatus::Cancelled | TaskStatus::Failed
+        )
+    }
+}
+
+/// 任务操作错误
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TaskError {
+    /// 没有正在运行的任务
+    NoRunningTask,
+    /// 任务不存在
+    NotFound(String),
+    /// 任务当前状态不允许该操作
+    InvalidStatus { name: String, status: TaskStatus },
+}
+
+impl std::fmt::Display for TaskError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TaskError::NoRunningTask => write!(f, "No current task running"),
+            TaskError::NotFound(name) => write!(f, "Task '{}' not found", name),
+            TaskError::InvalidStatus { name, status } => {
+                write!(f, "Task '{}' is {:?}, operation not allowed", name, status)
+            }
+        }
+    }
 }
 
-/// 带状态的任务（保留供未来 API 扩展使用）
-#[derive(Debug, Clone, Serialize)]
-#[allow(dead_code)]
+impl std::error::Error for TaskError {}
+
+/// 带状态的任务（任务队列中的一项）
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TaskWithStatus {
     #[serde(flatten)]
     pub task: Task,
     pub status: TaskStatus,
+    /// 注册时间
+    pub created_at: DateTime<Utc>,
+    /// 最近一次开始运行的时间
+    pub started_at: Option<DateTime<Utc>>,
+    /// 开始运行后，首次有节点上报该任务的容器处于 Running 的时间（自动完成据此判断任务已被执行）
+    pub first_run_at: Option<DateTime<Utc>>,
+    /// 结束时间（Completed/Cancelled/Failed）
+    pub completed_at: Option<DateTime<Utc>>,
 }
 
 /// 计算节点
@@ -75,9 +374,55 @@ pub struct Node {
     pub runtime_status: Option<NodeRuntimeStatus>,
     /// 活跃容器数量
     pub active_containers: u32,
-    /// 是否请求停止（管理员优雅退出指令）
-    #[serde(skip)]  // 不序列化到客户端
-    pub stop_requested: bool,
+    /// 管理员设置的运行模式（正常、排空、停止）
+    #[serde(default)]
+    pub mode: NodeMode,
+    /// 管理员设置的并行容器数（None 表示使用节点本地配置，即 cpu_count）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parallelism: Option<u32>,
+    /// 分配给该节点的任务（由调度器计算）
+    #[serde(default)]
+    pub assigned_task: Option<String>,
+    /// 该节点为分配任务运行的容器数（None 表示使用节点的全部容量，见 capacity）
+    #[serde(default)]
+    pub assigned_instances: Option<u32>,
+    /// 节点标签（GridNode 配置文件中的 labels，用于任务的 node_selector）
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// 标记为 Offline 的时间（在线时为 None）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offline_since: Option<DateTime<Utc>>,
+    /// 标记为 Offline 的原因（在线时为 None）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offline_reason: Option<OfflineReason>,
+    /// 是否通过客户端证书注册（之后只接受该节点的证书，不接受共享的节点密钥或专属凭据）
+    #[serde(default)]
+    pub registered_by_certificate: bool,
+    /// 最近一次心跳上报的各工作实例状态（不持久化，只在节点详情中返回）
+    #[serde(skip)]
+    pub instances: Vec<InstanceStatus>,
+}
+
+impl Node {
+    /// 节点实际运行的容器数上限（管理员设置的并行度，不超过 cpu_count）
+    pub fn capacity(&self) -> u32 {
+        self.parallelism.map_or(self.cpu_count, |n| n.min(self.cpu_count))
+    }
+
+    /// 根据架构确定 Docker 平台
+    pub fn platform(&self) -> &'static str {
+        platform_for_architecture(&self.architecture)
+    }
+}
+
+/// 根据 CPU 架构确定 Docker 平台
+pub fn platform_for_architecture(architecture: &str) -> &'static str {
+    match architecture {
+        "x86_64" => "linux/amd64",
+        "aarch64" => "linux/arm64",
+        "arm" => "linux/arm/v7",
+        _ => "linux/amd64", // 默认
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -86,6 +431,37 @@ pub enum NodeStatus {
     Offline,
 }
 
+/// 节点运行模式（由管理员设置，节点在心跳响应中收到）
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NodeMode {
+    /// 正常运行，参与任务分配
+    #[default]
+    Active,
+    /// 排空：不再分配新任务，也不启动新容器，运行中的容器自然结束
+    Draining,
+    /// 停止：节点停止所有容器后退出
+    Stopping,
+}
+
+impl NodeMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NodeMode::Active => "active",
+            NodeMode::Draining => "draining",
+            NodeMode::Stopping => "stopping",
+        }
+    }
+}
+
+/// 节点标记为 Offline 的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OfflineReason {
+    /// 心跳超时
+    HeartbeatTimeout,
+    /// 管理员请求停止后节点不再心跳（正常退出）
+    Stopped,
+}
+
 /// 注册节点请求
 #[derive(Debug, Deserialize)]
 pub struct RegisterNodeRequest {
@@ -93,6 +469,12 @@ pub struct RegisterNodeRequest {
     pub hostname: String,
     pub architecture: String,
     pub cpu_count: u32,
+    /// 节点标签（旧版 GridNode 不发送）
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    /// 是否申请节点专属凭据（签发后该节点只能用专属凭据访问节点接口）
+    #[serde(default)]
+    pub request_credential: bool,
 }
 
 /// 节点注册响应
@@ -100,6 +482,11 @@ pub struct RegisterNodeRequest {
 pub struct RegisterNodeResponse {
     pub node_id: String,
     pub current_task: Option<TaskConfig>,
+    /// 新签发的节点专属凭据（仅在申请时返回，服务端只保存哈希）
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_secret: Option<String>,
+    /// 管理员设置的并行容器数（None 表示使用节点本地配置）
+    pub parallelism: Option<u32>,
 }
 
 /// 任务配置（返回给节点的）
@@ -112,6 +499,40 @@ pub struct TaskConfig {
     pub output_redis: Option<String>,
     pub input_queue: Option<String>,
     pub output_queue: Option<String>,
+    /// 该节点为此任务运行的容器数（None 表示按节点并行度运行）
+    pub instances: Option<u32>,
+    /// 每个容器的资源需求（None 表示使用节点默认值）
+    pub resources: Option<TaskResources>,
+    /// 额外的容器环境变量
+    pub env: Option<HashMap<String, String>>,
+    /// 覆盖镜像的 CMD
+    pub command: Option<Vec<String>>,
+    /// 覆盖镜像的 ENTRYPOINT
+    pub entrypoint: Option<Vec<String>>,
+    /// 任务修订号（任务名相同但修订号变化时节点需要重启容器）
+    pub revision: u64,
+}
+
+impl TaskConfig {
+    /// 根据节点平台生成任务配置，任务没有该平台的镜像时返回 None
+    pub fn for_platform(task: &Task, platform: &str) -> Option<Self> {
+        let image = task.get_image_for_platform(platform)?;
+        Some(TaskConfig {
+            task_name: task.name.clone(),
+            image,
+            redis_url: None,
+            input_redis: task.input_redis.clone(),
+            output_redis: task.output_redis.clone(),
+            input_queue: task.input_queue.clone(),
+            output_queue: task.output_queue.clone(),
+            instances: None,
+            resources: task.resources.clone(),
+            env: task.env.clone(),
+            command: task.command.clone(),
+            entrypoint: task.entrypoint.clone(),
+            revision: task.revision,
+        })
+    }
 }
 
 /// 心跳请求
@@ -120,12 +541,48 @@ pub struct HeartbeatRequest {
     pub node_id: String,
     pub status: NodeRuntimeStatus,
     pub active_containers: u32,
+    /// 各工作实例的容器状态（旧版 GridNode 不上报）
+    #[serde(default)]
+    pub instances: Vec<InstanceStatus>,
+}
+
+/// GridNode 工作实例的容器状态
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstanceStatus {
+    pub instance_id: u32,
+    pub state: InstanceState,
+    /// 当前任务名
+    pub task: Option<String>,
+    /// 当前任务的镜像
+    pub image: Option<String>,
+    /// 运行中的容器 ID
+    pub container_id: Option<String>,
+    /// 上一个容器的退出码
+    pub last_exit_code: Option<i64>,
+    /// 最近的错误信息
+    pub error: Option<String>,
+    /// 当前任务（修订）的容器重启次数：启动失败后重试的次数，0 表示首次启动即成功
+    /// 任务或修订变化时清零；容器退出后不会自动重新启动
+    pub restart_count: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InstanceState {
+    Idle,
+    Pulling,
+    Running,
+    Exited,
+    Failed,
 }
 
 /// 心跳响应
 #[derive(Debug, Serialize)]
 pub struct HeartbeatResponse {
+    /// mode 为 Stopping（兼容只认识该字段的旧版 GridNode）
     pub stop_requested: bool,
+    pub mode: NodeMode,
+    /// 管理员设置的并行容器数（None 表示使用节点本地配置）
+    pub parallelism: Option<u32>,
 }
 
 #[derive(Debug, Clone, Copy, Deserialize, Serialize)]
@@ -135,6 +592,16 @@ pub enum NodeRuntimeStatus {
     Error,
 }
 
+impl NodeRuntimeStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NodeRuntimeStatus::Running => "running",
+            NodeRuntimeStatus::Idle => "idle",
+            NodeRuntimeStatus::Error => "error",
+        }
+    }
+}
+
 /// 应用状态（共享）
 pub type AppState = Arc<RwLock<AppStateInner>>;
 
@@ -142,12 +609,28 @@ pub type AppState = Arc<RwLock<AppStateInner>>;
 pub struct AppStateInner {
     /// 服务器配置
     pub config: ServerConfig,
-    /// 所有注册的任务，按顺序
-    pub tasks: Vec<(Task, TaskStatus)>,
-    /// 当前运行任务的索引（None 表示未开始）
-    pub current_task_index: Option<usize>,
+    /// 所有注册的任务，按顺序（可同时有多个 Running，上限为 max_running_tasks）
+    pub tasks: Vec<TaskWithStatus>,
     /// 在线节点
     pub nodes: HashMap<String, Node>,
+    /// 节点专属凭据（node_id -> 密钥的 SHA-256），节点离线被清理后仍然保留
+    node_credentials: HashMap<String, String>,
+    /// 已吊销的节点 ID（不能再以该 ID 注册）
+    revoked_nodes: HashSet<String>,
+    /// 持久化存储（None 表示仅内存，或正在重放日志）
+    store: Option<StateStore>,
+    /// 重放日志时使用记录中的时间，而不是当前时间
+    replay_clock: Option<DateTime<Utc>>,
+    /// 正在重放日志（不计入指标）
+    replaying: bool,
+    /// Prometheus 指标
+    pub metrics: Metrics,
+    /// 管理操作审计日志（None 表示未启用）
+    audit: Option<AuditLog>,
+    /// 任务和节点生命周期事件（/api/events）
+    pub events: EventBus,
+    /// 节点任务分配的变更序号（每次重新分配后加 1），等待任务变化的节点订阅它
+    assignments: watch::Sender<u64>,
 }
 
 impl AppStateInner {
@@ -155,111 +638,852 @@ impl AppStateInner {
         Self {
             config,
             tasks: Vec::new(),
-            current_task_index: None,
             nodes: HashMap::new(),
+            node_credentials: HashMap::new(),
+            revoked_nodes: HashSet::new(),
+            store: None,
+            replay_clock: None,
+            replaying: false,
+            metrics: Metrics::new(),
+            audit: None,
+            events: EventBus::new(),
+            assignments: watch::Sender::new(0),
         }
     }
 
-    /// 获取当前任务
+    /// 当前时间（重放日志时为日志记录的时间）
+    fn now(&self) -> DateTime<Utc> {
+        self.replay_clock.unwrap_or_else(Utc::now)
+    }
+
+    /// 从持久化存储恢复状态（启动时调用）
+    /// 加载快照后按顺序重放日志，然后写入新快照并清空日志
+    pub fn restore(&mut self, mut store: StateStore) -> anyhow::Result<()> {
+        let (snapshot, entries) = store.load()?;
+
+        if let Some(snapshot) = snapshot {
+            self.tasks = snapshot.tasks;
+            self.nodes = snapshot.nodes;
+            self.node_credentials = snapshot.node_credentials;
+            self.revoked_nodes = snapshot.revoked_nodes;
+        }
+
+        // 重放期间 store 为 None，不会重复写日志
+        let replayed = entries.len();
+        for record in entries {
+            self.replay(record);
+        }
+
+        // 恢复的在线节点视为刚刚上线，给它们一个完整的心跳超时窗口重新连接
+        // Offline 节点保留离线时间，保留期限从最初离线时开始计算
+        let now = Utc::now();
+        for node in self.nodes.values_mut() {
+            if node.status == NodeStatus::Online {
+                node.last_seen = now;
+            }
+        }
+        self.rebalance();
+
+        store.write_snapshot(self.snapshot())?;
+        tracing::info!(
+            "Restored state from {} ({} tasks, {} nodes, {} journal entries replayed)",
+            store.dir().display(),
+            self.tasks.len(),
+            self.nodes.len(),
+            replayed
+        );
+        self.store = Some(store);
+        Ok(())
+    }
+
+    /// 重放一条日志
+    fn replay(&mut self, record: JournalRecord) {
+        self.replay_clock = Some(record.at);
+        self.replaying = true;
+        self.apply(record.entry);
+        self.replaying = false;
+        self.replay_clock = None;
+    }
+
+    fn apply(&mut self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::AddTask { task } => self.add_task(task),
+            JournalEntry::NextTask => {
+                self.next_task();
+            }
+            JournalEntry::FinishTask { name } => {
+                let _ = self.finish_current_task(name.as_deref());
+            }
+            JournalEntry::CancelTask { name } => {
+                let _ = self.cancel_current_task(name.as_deref());
+            }
+            JournalEntry::FailTask { name } => {
+                let _ = self.fail_current_task(name.as_deref());
+            }
+            JournalEntry::DeleteTask { name } => {
+                let _ = self.delete_task(&name);
+            }
+            JournalEntry::RequeueTask { name } => {
+                let _ = self.requeue_task(&name);
+            }
+            JournalEntry::RepositionTask {
+                name,
+                priority,
+                position,
+            } => {
+                let _ = self.reposition_task(&name, priority, position);
+            }
+            JournalEntry::UpdateTask { name, task } => {
+                let _ = self.update_task(&name, task);
+            }
+            JournalEntry::MarkTaskRun { name } => self.mark_task_run(&name),
+            JournalEntry::RegisterNode {
+                node,
+                credential_hash,
+            } => {
+                let _ = self.register_node(node, credential_hash);
+            }
+            JournalEntry::RevokeNode { node_id } => {
+                self.revoke_node(&node_id);
+            }
+            JournalEntry::SetNodeMode { node_id, mode } => {
+                self.set_node_mode(&node_id, mode);
+            }
+            JournalEntry::SetNodeParallelism {
+                node_id,
+                parallelism,
+            } => {
+                self.set_node_parallelism(&node_id, parallelism);
+            }
+            JournalEntry::NodeOffline {
+                node_id,
+                since,
+                reason,
+            } => {
+                self.mark_node_offline(&node_id, since, reason);
+                self.rebalance();
+            }
+            JournalEntry::NodeOnline { node_id } => {
+                if let Some(node) = self.nodes.get_mut(&node_id) {
+                    node.status = NodeStatus::Online;
+                    node.offline_since = None;
+                    node.offline_reason = None;
+                }
+                self.rebalance();
+            }
+            JournalEntry::PurgeNode { node_id } => {
+                self.purge_node(&node_id);
+            }
+        }
+    }
+
+    /// 当前状态的快照
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            tasks: self.tasks.clone(),
+            nodes: self.nodes.clone(),
+            node_credentials: self.node_credentials.clone(),
+            revoked_nodes: self.revoked_nodes.clone(),
+            // 由 StateStore::write_snapshot 填写
+            journal_seq: 0,
+        }
+    }
+
+    /// 记录一次状态变更，日志过长时自动压缩
+    /// 写入失败只记录错误，不影响内存中的状态
+    fn persist(&mut self, entry: JournalEntry) {
+        let at = self.now();
+        let Some(store) = self.store.as_mut() else {
+            return;
+        };
+        if let Err(e) = store.append(at, entry) {
+            tracing::error!("Failed to append to state journal: {}", e);
+        }
+
+        if self.store.as_ref().is_some_and(StateStore::needs_compaction) {
+            let snapshot = self.snapshot();
+            if let Some(store) = self.store.as_mut()
+                && let Err(e) = store.write_snapshot(snapshot)
+            {
+                tracing::error!("Failed to write state snapshot: {}", e);
+            }
+        }
+    }
+
+    /// 应用热重载的配置（见 ServerConfig::reload_from），配置无效时保持当前配置
+    /// max_running_tasks 调大时立即开始排队的任务；调小时不会停止已在运行的任务
+    pub fn reload_config(&mut self, new: ServerConfig) -> anyhow::Result<ConfigChanges> {
+        let (config, changes) = self.config.reload_from(new)?;
+        self.config = config;
+
+        if changes.applied.contains(&"max_running_tasks") {
+            self.schedule();
+            // 重放日志的结果取决于 max_running_tasks，写入快照使之后的日志基于新配置
+            let snapshot = self.snapshot();
+            if let Some(store) = self.store.as_mut()
+                && let Err(e) = store.write_snapshot(snapshot)
+            {
+                tracing::error!("Failed to write state snapshot: {}", e);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// 启用审计日志
+    pub fn set_audit_log(&mut self, audit: AuditLog) {
+        self.audit = Some(audit);
+    }
+
+    /// 审计日志（未启用时为 None）
+    pub fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit.as_ref()
+    }
+
+    /// 记录一条管理操作，写入失败只记录错误，不影响操作本身
+    pub fn record_audit(
+        &mut self,
+        actor: &str,
+        action: &str,
+        target: &str,
+        before: Option<serde_json::Value>,
+        after: Option<serde_json::Value>,
+    ) {
+        let Some(audit) = self.audit.as_mut() else {
+            return;
+        };
+        let record = AuditRecord {
+            at: Utc::now(),
+            actor: actor.to_string(),
+            action: action.to_string(),
+            target: target.to_string(),
+            before,
+            after,
+        };
+        if let Err(e) = audit.append(&record) {
+            tracing::error!("Failed to append to audit log: {}", e);
+        }
+    }
+
+    /// 任务当前状态（用于审计记录，任务不存在时为 None）
+    pub fn task_state(&self, name: &str) -> Option<serde_json::Value> {
+        self.tasks
+            .iter()
+            .find(|entry| entry.task.name == name)
+            .and_then(|entry| serde_json::to_value(entry).ok())
+    }
+
+    /// 节点当前状态（用于审计记录，节点不在线时为 None）
+    pub fn node_state(&self, node_id: &str) -> Option<serde_json::Value> {
+        self.nodes
+            .get(node_id)
+            .and_then(|node| serde_json::to_value(node).ok())
+    }
+
+    /// 订阅任务分配变化（返回的 Receiver 已把当前序号标记为已读）
+    pub fn subscribe_assignments(&self) -> watch::Receiver<u64> {
+        self.assignments.subscribe()
+    }
+
+    /// 记录任务状态变化：更新指标并发布事件（重放日志时跳过）
+    fn record_transition(&mut self, status: TaskStatus, event: Event) {
+        if !self.replaying {
+            self.metrics.task_transition(status);
+        }
+        self.emit(event);
+    }
+
+    /// 发布生命周期事件（重放日志时跳过，避免重启后重复发布历史事件）
+    fn emit(&mut self, event: Event) {
+        if !self.replaying {
+            self.events.publish(event);
+        }
+    }
+
+    /// 获取主任务：队列中最靠前的 Running 任务
+    /// 只允许一个任务运行时（默认），即为当前任务
     pub fn get_current_task(&self) -> Option<&Task> {
-        self.current_task_index.and_then(|idx| {
-            self.tasks.get(idx).map(|(task, _)| task)
-        })
+        self.primary_running_index().map(|idx| &self.tasks[idx].task)
+    }
+
+    /// 所有 Running 任务的索引（按队列顺序）
+    pub fn running_indices(&self) -> Vec<usize> {
+        self.tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.status == TaskStatus::Running)
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    fn primary_running_index(&self) -> Option<usize> {
+        self.tasks
+            .iter()
+            .position(|entry| entry.status == TaskStatus::Running)
+    }
+
+    /// Pending 任务的调度顺序（索引列表）
+    /// 优先级高的在前，同优先级按队列顺序（注册顺序，可通过 reposition 调整）
+    pub fn pending_order(&self) -> Vec<usize> {
+        let mut order: Vec<usize> = self
+            .tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.status == TaskStatus::Pending)
+            .map(|(idx, _)| idx)
+            .collect();
+        order.sort_by_key(|&idx| (std::cmp::Reverse(self.tasks[idx].task.priority), idx));
+        order
+    }
+
+    /// 调度：按调度顺序开始 Pending 任务，直到 Running 任务数达到 max_running_tasks，
+    /// 然后重新分配节点
+    /// 返回新开始的任务名
+    fn schedule(&mut self) -> Vec<String> {
+        let max_running = self.config.max_running_tasks.max(1);
+        let free_slots = max_running.saturating_sub(self.running_indices().len());
+
+        let now = self.now();
+        let mut started = Vec::new();
+        for idx in self.pending_order().into_iter().take(free_slots) {
+            let entry = &mut self.tasks[idx];
+            entry.status = TaskStatus::Running;
+            entry.started_at = Some(now);
+            entry.first_run_at = None;
+            let name = entry.task.name.clone();
+            started.push(name.clone());
+            self.record_transition(TaskStatus::Running, Event::TaskStarted { task: name });
+        }
+
+        self.rebalance();
+        started
     }
 
     /// 添加新任务到队列末尾
-    /// 如果是第一个任务，或当前没有运行中的任务，自动开始执行
+    /// 如果 Running 任务数未达上限（默认 1，即当前没有任务在运行），自动开始执行
     pub fn add_task(&mut self, task: Task) {
-        let is_first_task = self.tasks.is_empty();
-        
-        // 检查是否有任务正在运行
-        let has_running_task = self.current_task_index
-            .and_then(|idx| self.tasks.get(idx))
-            .map(|(_, status)| *status == TaskStatus::Running)
-            .unwrap_or(false);
-        
-        self.tasks.push((task, TaskStatus::Pending));
-        
-        // 自动开始执行的条件：
-        // 1. 这是第一个注册的任务
-        // 2. 或者当前没有任务在运行（所有任务都已完成或从未开始）
-        if is_first_task || !has_running_task {
-            let new_index = self.tasks.len() - 1;
-            self.current_task_index = Some(new_index);
-            if let Some((_, status)) = self.tasks.get_mut(new_index) {
-                *status = TaskStatus::Running;
-            }
-        }
+        self.tasks.push(TaskWithStatus {
+            task: task.clone(),
+            status: TaskStatus::Pending,
+            created_at: self.now(),
+            started_at: None,
+            first_run_at: None,
+            completed_at: None,
+        });
+        self.record_transition(
+            TaskStatus::Pending,
+            Event::TaskCreated {
+                task: task.name.clone(),
+            },
+        );
+        self.schedule();
+        self.persist(JournalEntry::AddTask { task });
     }
 
     /// 切换到下一个任务（人工调用）
     /// 返回 (上一个任务名, 新任务名)
     pub fn next_task(&mut self) -> Option<(String, String)> {
-        let prev_idx = self.current_task_index;
-        
-        // 先获取上一个任务的名字（在修改前）
-        let prev_name = prev_idx
-            .and_then(|i| self.tasks.get(i))
-            .map(|(t, _)| t.name.clone())
-            .unwrap_or_else(|| "none".to_string());
-        
-        // 标记上一个任务完成
-        if let Some(idx) = prev_idx {
-            if let Some((_, status)) = self.tasks.get_mut(idx) {
-                *status = TaskStatus::Completed;
+        // 标记上一个任务完成（先获取名字）
+        let now = self.now();
+        let prev_name = match self.primary_running_index() {
+            Some(idx) => {
+                let entry = &mut self.tasks[idx];
+                entry.status = TaskStatus::Completed;
+                entry.completed_at = Some(now);
+                let name = entry.task.name.clone();
+                self.record_transition(
+                    TaskStatus::Completed,
+                    Event::TaskFinished {
+                        task: name.clone(),
+                        status: TaskStatus::Completed,
+                    },
+                );
+                name
             }
-        }
+            None => "none".to_string(),
+        };
 
         // 找到下一个 pending 任务
-        let next_idx = prev_idx.map(|i| i + 1).unwrap_or(0);
+        let switched = self
+            .schedule()
+            .into_iter()
+            .next()
+            .map(|current| (prev_name, current));
         
-        if next_idx < self.tasks.len() {
-            self.current_task_index = Some(next_idx);
-            if let Some((task, status)) = self.tasks.get_mut(next_idx) {
-                *status = TaskStatus::Running;
-                return Some((prev_name, task.name.clone()));
+        self.persist(JournalEntry::NextTask);
+        switched
+    }
+
+    /// 完成任务（finish API）
+    /// name 为 None 时完成主任务；标记为完成，如果有下一个则自动开始
+    /// 返回: (完成的任务名, 新开始的任务名(可能没有))
+    /// 错误: 没有任务在运行 / 指定任务未在运行
+    pub fn finish_current_task(
+        &mut self,
+        name: Option<&str>,
+    ) -> Result<(String, Option<String>), TaskError> {
+        let result = self.end_running_task(name, TaskStatus::Completed)?;
+        self.persist(JournalEntry::FinishTask {
+            name: name.map(str::to_string),
+        });
+        Ok(result)
+    }
+
+    /// 取消任务（与 finish 不同，任务记为 Cancelled）
+    /// 返回: (取消的任务名, 新开始的任务名(可能没有))
+    pub fn cancel_current_task(
+        &mut self,
+        name: Option<&str>,
+    ) -> Result<(String, Option<String>), TaskError> {
+        let result = self.end_running_task(name, TaskStatus::Cancelled)?;
+        self.persist(JournalEntry::CancelTask {
+            name: name.map(str::to_string),
+        });
+        Ok(result)
+    }
+
+    /// 将任务标记为失败
+    /// 返回: (失败的任务名, 新开始的任务名(可能没有))
+    pub fn fail_current_task(
+        &mut self,
+        name: Option<&str>,
+    ) -> Result<(String, Option<String>), TaskError> {
+        let result = self.end_running_task(name, TaskStatus::Failed)?;
+        self.persist(JournalEntry::FailTask {
+            name: name.map(str::to_string),
+        });
+        Ok(result)
+    }
+
+    /// 以指定的终止状态结束一个 Running 任务，并开始下一个 Pending 任务
+    fn end_running_task(
+        &mut self,
+        name: Option<&str>,
+        final_status: TaskStatus,
+    ) -> Result<(String, Option<String>), TaskError> {
+        let idx = match name {
+            Some(name) => {
+                let idx = self.find_task(name)?;
+                let status = self.tasks[idx].status;
+                if status != TaskStatus::Running {
+                    return Err(TaskError::InvalidStatus {
+                        name: name.to_string(),
+                        status,
+                    });
+                }
+                idx
             }
+            None => self.primary_running_index().ok_or(TaskError::NoRunningTask)?,
+        };
+
+        let now = self.now();
+        let entry = &mut self.tasks[idx];
+        entry.status = final_status;
+        entry.completed_at = Some(now);
+        let prev_name = entry.task.name.clone();
+        self.record_transition(
+            final_status,
+            Event::TaskFinished {
+                task: prev_name.clone(),
+                status: final_status,
+            },
+        );
+
+        // 没有下一个任务时 started 为 None，结束操作本身仍是成功的
+        let started = self.schedule().into_iter().next();
+        Ok((prev_name, started))
+    }
+
+    /// 删除一个未在运行的任务（如误注册的 Pending 任务）
+    /// 运行中的任务需要先 cancel
+    pub fn delete_task(&mut self, name: &str) -> Result<Task, TaskError> {
+        let idx = self.find_task(name)?;
+        let status = self.tasks[idx].status;
+        if status == TaskStatus::Running {
+            return Err(TaskError::InvalidStatus {
+                name: name.to_string(),
+                status,
+            });
         }
-        
-        None
+
+        let task = self.tasks.remove(idx).task;
+        if !self.replaying {
+            self.metrics.task_deleted(status);
+        }
+        self.emit(Event::TaskDeleted {
+            task: name.to_string(),
+            status,
+        });
+        self.persist(JournalEntry::DeleteTask {
+            name: name.to_string(),
+        });
+        Ok(task)
     }
 
-    /// 完成当前任务（finish API）
-    /// 标记当前任务为完成，如果有下一个则自动开始
-    /// 返回: (完成的任务名, 新开始的任务名(可能没有))
-    /// 错误: 没有当前任务在运行
-    pub fn finish_current_task(&mut self) -> Result<(String, Option<String>), &'static str> {
-        // 必须有当前任务在运行
-        let prev_idx = self.current_task_index.ok_or("No current task running")?;
-        
-        // 获取当前任务名
-        let prev_name = self.tasks.get(prev_idx)
-            .map(|(t, _)| t.name.clone())
-            .ok_or("Current task index invalid")?;
-        
-        // 标记当前任务完成
-        if let Some((_, status)) = self.tasks.get_mut(prev_idx) {
-            *status = TaskStatus::Completed;
+    /// 重新排队一个已结束的任务（Completed/Cancelled/Failed）
+    /// 任务移到队列末尾并设为 Pending，如果 Running 任务数未达上限则立即开始
+    /// 返回: 任务是否已立即开始
+    pub fn requeue_task(&mut self, name: &str) -> Result<bool, TaskError> {
+        let idx = self.find_task(name)?;
+        let status = self.tasks[idx].status;
+        if !status.is_finished() {
+            return Err(TaskError::InvalidStatus {
+                name: name.to_string(),
+                status,
+            });
         }
 
-        // 尝试找到下一个 pending 任务
-        let next_idx = prev_idx + 1;
-        
-        if next_idx < self.tasks.len() {
-            self.current_task_index = Some(next_idx);
-            if let Some((task, status)) = self.tasks.get_mut(next_idx) {
-                *status = TaskStatus::Running;
-                return Ok((prev_name, Some(task.name.clone())));
-            }
+        let mut entry = self.tasks.remove(idx);
+        entry.status = TaskStatus::Pending;
+        entry.started_at = None;
+        entry.first_run_at = None;
+        entry.completed_at = None;
+        self.tasks.push(entry);
+        self.record_transition(
+            TaskStatus::Pending,
+            Event::TaskRequeued {
+                task: name.to_string(),
+            },
+        );
+        let started = self.schedule().iter().any(|started| started == name);
+
+        self.persist(JournalEntry::RequeueTask {
+            name: name.to_string(),
+        });
+        Ok(started)
+    }
+
+    /// 调整 Pending 任务的优先级和/或队列位置
+    /// position 为该任务在调度顺序（pending_order）中的新位置，0 表示下一个执行
+    /// 调度时先比较优先级，position 会被限制在同优先级任务所在的区间内
+    pub fn reposition_task(
+        &mut self,
+        name: &str,
+        priority: Option<i32>,
+        position: Option<usize>,
+    ) -> Result<(), TaskError> {
+        let idx = self.find_task(name)?;
+        let status = self.tasks[idx].status;
+        if status != TaskStatus::Pending {
+            return Err(TaskError::InvalidStatus {
+                name: name.to_string(),
+                status,
+            });
         }
-        
-        // 没有下一个任务，完成操作本身仍是成功的
-        Ok((prev_name, None))
+
+        if let Some(priority) = priority {
+            self.tasks[idx].task.priority = priority;
+        }
+
+        if let Some(position) = position {
+            let entry = self.tasks.remove(idx);
+            let priority = entry.task.priority;
+            // 其余 Pending 任务的调度顺序中，同优先级任务占据 [first, end) 区间
+            let order = self.pending_order();
+            let first = order
+                .iter()
+                .take_while(|&&i| self.tasks[i].task.priority > priority)
+                .count();
+            let end = first
+                + order[first..]
+                    .iter()
+                    .take_while(|&&i| self.tasks[i].task.priority == priority)
+                    .count();
+            // 插入到该位置上的同优先级任务之前；超出区间则排在最后一个同优先级任务之后
+            let insert_at = match position.clamp(first, end) {
+                at if at < end => order[at],
+                _ if end > first => order[end - 1] + 1,
+                _ => self.tasks.len(),
+            };
+            self.tasks.insert(insert_at, entry);
+        }
+
+        self.persist(JournalEntry::RepositionTask {
+            name: name.to_string(),
+            priority,
+            position,
+        });
+        Ok(())
+    }
+
+    /// 原地更新未结束任务（Pending/Running）的定义
+    /// 任务名、优先级和修订号不能通过该接口修改；下发给节点的配置变化时修订号加 1，
+    /// 正在执行该任务的节点会在下次获取任务时重启容器
+    /// 返回更新后的修订号
+    pub fn update_task(&mut self, name: &str, mut task: Task) -> Result<u64, TaskError> {
+        let idx = self.find_task(name)?;
+        let status = self.tasks[idx].status;
+        if status.is_finished() {
+            return Err(TaskError::InvalidStatus {
+                name: name.to_string(),
+                status,
+            });
+        }
+
+        let old = &self.tasks[idx].task;
+        task.name = old.name.clone();
+        task.priority = old.priority;
+        task.revision = if task.same_node_config(old) {
+            old.revision
+        } else {
+            old.revision + 1
+        };
+        let revision = task.revision;
+        self.tasks[idx].task = task.clone();
+
+        // weight / max_containers 可能变化，重新分配节点
+        if status == TaskStatus::Running {
+            self.rebalance();
+        }
+
+        self.persist(JournalEntry::UpdateTask {
+            name: name.to_string(),
+            task,
+        });
+        Ok(revision)
+    }
+
+    /// 获取任务详情（完整定义、状态、时间戳、正在执行的节点）
+    pub fn task_detail(&self, name: &str) -> Result<TaskDetailResponse, TaskError> {
+        let entry = &self.tasks[self.find_task(name)?];
+
+        let mut nodes: Vec<TaskNodeInfo> = if entry.status == TaskStatus::Running {
+            self.nodes
+                .values()
+                .filter(|node| node.assigned_task.as_deref() == Some(name))
+                .map(|node| TaskNodeInfo {
+                    id: node.id.clone(),
+                    hostname: node.hostname.clone(),
+                    active_containers: node.active_containers,
+                    last_seen: node.last_seen,
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        nodes.sort_by(|a, b| a.id.cmp(&b.id));
+
+        Ok(TaskDetailResponse {
+            task: entry.clone(),
+            nodes,
+        })
+    }
+
+    /// 是否已存在同名任务（任何状态）
+    pub fn has_task(&self, name: &str) -> bool {
+        self.tasks.iter().any(|entry| entry.task.name == name)
+    }
+
+    /// 按名称查找任务索引
+    fn find_task(&self, name: &str) -> Result<usize, TaskError> {
+        self.tasks
+            .iter()
+            .position(|entry| entry.task.name == name)
+            .ok_or_else(|| TaskError::NotFound(name.to_string()))
     }
 
     /// 注册或更新节点
-    pub fn register_node(&mut self, node: Node) {
-        self.nodes.insert(node.id.clone(), node);
+    /// credential_hash 不为 None 时为节点设置（或轮换）专属凭据
+    /// 已吊销的节点不能注册，返回 false
+    pub fn register_node(&mut self, mut node: Node, credential_hash: Option<String>) -> bool {
+        if self.revoked_nodes.contains(&node.id) {
+            return false;
+        }
+        // 重新注册的节点保留原有任务分配，避免无谓的任务切换
+        // 排空模式和并行度在节点重启后仍然有效；停止请求已经完成，重新注册后恢复正常
+        if let Some(existing) = self.nodes.get(&node.id) {
+            node.assigned_task = existing.assigned_task.clone();
+            node.assigned_instances = existing.assigned_instances;
+            if existing.mode == NodeMode::Draining {
+                node.mode = NodeMode::Draining;
+            }
+            node.parallelism = existing.parallelism;
+            node.registered_by_certificate |= existing.registered_by_certificate;
+        }
+        if let Some(hash) = &credential_hash {
+            self.node_credentials.insert(node.id.clone(), hash.clone());
+        }
+        self.nodes.insert(node.id.clone(), node.clone());
+        self.rebalance();
+        self.emit(Event::NodeRegistered {
+            node_id: node.id.clone(),
+            hostname: node.hostname.clone(),
+        });
+        self.persist(JournalEntry::RegisterNode {
+            node,
+            credential_hash,
+        });
+        true
+    }
+
+    /// 吊销节点：移出节点列表，之后不能再以该 ID 注册
+    /// 专属凭据的哈希保留，用于识别被吊销的节点：其请求返回 403（而不是 401），GridNode 据此停止并退出
+    /// 返回节点是否存在（在线或持有凭据）
+    pub fn revoke_node(&mut self, node_id: &str) -> bool {
+        let existed = self.nodes.remove(node_id).is_some()
+            | self.node_credentials.contains_key(node_id);
+        self.revoked_nodes.insert(node_id.to_string());
+        self.rebalance();
+        self.emit(Event::NodeRevoked {
+            node_id: node_id.to_string(),
+        });
+        self.persist(JournalEntry::RevokeNode {
+            node_id: node_id.to_string(),
+        });
+        existed
+    }
+
+    /// 节点是否已被吊销
+    pub fn is_node_revoked(&self, node_id: &str) -> bool {
+        self.revoked_nodes.contains(node_id)
+    }
+
+    /// 节点是否持有专属凭据（持有时只能用该凭据访问节点接口）
+    pub fn node_has_credential(&self, node_id: &str) -> bool {
+        self.node_credentials.contains_key(node_id)
+    }
+
+    /// 节点是否通过客户端证书注册
+    pub fn node_uses_certificate(&self, node_id: &str) -> bool {
+        self.nodes
+            .get(node_id)
+            .is_some_and(|node| node.registered_by_certificate)
+    }
+
+    /// 按密钥哈希查找节点 ID
+    pub fn node_for_credential(&self, hash: &str) -> Option<&str> {
+        self.node_credentials
+            .iter()
+            .find(|(_, stored)| stored.as_str() == hash)
+            .map(|(node_id, _)| node_id.as_str())
+    }
+
+    /// 获取分配给节点的任务配置
+    /// 返回 None 表示该节点当前没有任务（空闲）
+    pub fn task_config_for_node(&self, node_id: &str) -> Option<TaskConfig> {
+        let node = self.nodes.get(node_id)?;
+        let task_name = node.assigned_task.as_deref()?;
+        let entry = self
+            .tasks
+            .iter()
+            .find(|entry| entry.status == TaskStatus::Running && entry.task.name == task_name)?;
+        let mut config = TaskConfig::for_platform(&entry.task, node.platform())?;
+        config.instances = node.assigned_instances;
+        Some(config)
+    }
+
+    /// 按权重在在线节点之间分配 Running 任务（公平分享）
+    ///
+    /// 每个任务按 weight 占比获得目标 CPU 份额（受 max_containers 限制），每个节点整体分配给一个任务：
+    /// 只考虑任务能运行的节点（镜像支持该平台，且节点标签满足 node_selector）：
+    /// 1. 节点优先保留原有分配（任务仍在运行、节点仍满足要求、保留后更接近目标份额），避免频繁切换容器
+    /// 2. 其余节点按 CPU 数从大到小，分配给 已分配 CPU / 权重 最小且未达上限的任务
+    ///
+    /// 节点的容器数超出任务剩余 max_containers 时，通过 assigned_instances 限制该节点运行的容器数
+    fn rebalance(&mut self) {
+        let running: Vec<&Task> = self
+            .tasks
+            .iter()
+            .filter(|entry| entry.status == TaskStatus::Running)
+            .map(|entry| &entry.task)
+            .collect();
+
+        let mut node_ids: Vec<String> = self
+            .nodes
+            .values()
+            .filter(|node| node.status == NodeStatus::Online && node.mode == NodeMode::Active)
+            .map(|node| node.id.clone())
+            .collect();
+        node_ids.sort();
+
+        let total_cpu: u32 = node_ids.iter().map(|id| self.nodes[id].capacity()).sum();
+        let total_weight: u32 = running.iter().map(|task| task.weight.max(1)).sum();
+
+        // 每个任务的目标 CPU 份额
+        let targets: Vec<f64> = running
+            .iter()
+            .map(|task| {
+                let share = total_cpu as f64 * task.weight.max(1) as f64 / total_weight.max(1) as f64;
+                match task.max_containers {
+                    Some(max) => share.min(max as f64),
+                    None => share,
+                }
+            })
+            .collect();
+        let mut allocated = vec![0u32; running.len()];
+        let mut assignments: HashMap<String, (usize, Option<u32>)> = HashMap::new();
+
+        let remaining_cap = |t: usize, allocated: &[u32]| {
+            running[t]
+                .max_containers
+                .map(|max| max.saturating_sub(allocated[t]))
+        };
+
+        // 第一轮：保留原有分配
+        for id in &node_ids {
+            let node = &self.nodes[id];
+            let Some(t) = node.assigned_task.as_deref().and_then(|name| {
+                running.iter().position(|task| task.name == name)
+            }) else {
+                continue;
+            };
+            // 保留该节点后，已分配量应比不保留更接近目标份额
+            if !running[t].can_run_on(node)
+                || allocated[t] as f64 + node.capacity() as f64 / 2.0 > targets[t]
+                || remaining_cap(t, &allocated) == Some(0)
+            {
+                continue;
+            }
+            let instances = remaining_cap(t, &allocated).filter(|&cap| cap < node.capacity());
+            allocated[t] += instances.unwrap_or(node.capacity());
+            assignments.insert(id.clone(), (t, instances));
+        }
+
+        // 第二轮：分配剩余节点
+        let mut unassigned: Vec<&String> = node_ids
+            .iter()
+            .filter(|id| !assignments.contains_key(*id))
+            .collect();
+        unassigned.sort_by_key(|id| std::cmp::Reverse(self.nodes[*id].capacity()));
+        for id in unassigned {
+            let node = &self.nodes[id];
+            let candidate = (0..running.len())
+                .filter(|&t| running[t].can_run_on(node))
+                .filter(|&t| remaining_cap(t, &allocated) != Some(0))
+                .min_by(|&a, &b| {
+                    let load_a = allocated[a] as f64 / running[a].weight.max(1) as f64;
+                    let load_b = allocated[b] as f64 / running[b].weight.max(1) as f64;
+                    load_a.total_cmp(&load_b).then(a.cmp(&b))
+                });
+            if let Some(t) = candidate {
+                let instances = remaining_cap(t, &allocated).filter(|&cap| cap < node.capacity());
+                allocated[t] += instances.unwrap_or(node.capacity());
+                assignments.insert(id.clone(), (t, instances));
+            }
+        }
+
+        let names: Vec<String> = running.iter().map(|task| task.name.clone()).collect();
+        for node in self.nodes.values_mut() {
+            match assignments.get(&node.id) {
+                Some(&(t, instances)) => {
+                    node.assigned_task = Some(names[t].clone());
+                    node.assigned_instances = instances;
+                }
+                // 排空中的节点保留原有分配，运行中的容器自然结束；任务结束后才清除
+                None if node.mode == NodeMode::Draining
+                    && node.status == NodeStatus::Online
+                    && node
+                        .assigned_task
+                        .as_ref()
+                        .is_some_and(|name| names.contains(name)) => {}
+                None => {
+                    node.assigned_task = None;
+                    node.assigned_instances = None;
+                }
+            }
+        }
+
+        // 唤醒等待任务变化的节点（由节点自己比较配置是否真的变化）
+        self.assignments.send_modify(|seq| *seq += 1);
     }
 
     /// 更新节点心跳（带运行时状态）
@@ -268,36 +1492,192 @@ impl AppStateInner {
         node_id: &str,
         runtime_status: NodeRuntimeStatus,
         active_containers: u32,
+        instances: Vec<InstanceStatus>,
     ) -> bool {
-        if let Some(node) = self.nodes.get_mut(node_id) {
-            node.last_seen = Utc::now();
-            node.status = NodeStatus::Online;
-            node.runtime_status = Some(runtime_status);
-            node.active_containers = active_containers;
-
-            // 记录错误状态日志
-            if matches!(runtime_status, NodeRuntimeStatus::Error) {
-                tracing::warn!(
-                    "Node {} reported error status (active containers: {})",
-                    node_id,
-                    active_containers
-                );
+        let Some(node) = self.nodes.get_mut(node_id) else {
+            return false;
+        };
+        let was_error = matches!(node.runtime_status, Some(NodeRuntimeStatus::Error));
+        let was_offline = node.status == NodeStatus::Offline;
+        node.last_seen = Utc::now();
+        node.status = NodeStatus::Online;
+        node.offline_since = None;
+        node.offline_reason = None;
+        node.runtime_status = Some(runtime_status);
+        node.active_containers = active_containers;
+        let running_tasks: Vec<String> = instances
+            .iter()
+            .filter(|instance| instance.state == InstanceState::Running)
+            .filter_map(|instance| instance.task.clone())
+            .collect();
+        node.instances = instances;
+
+        for name in running_tasks {
+            self.mark_task_run(&name);
+        }
+
+        // Offline 节点恢复心跳后重新参与任务分配
+        if was_offline {
+            tracing::info!("Node {} is back online", node_id);
+            self.persist(JournalEntry::NodeOnline {
+                node_id: node_id.to_string(),
+            });
+            self.rebalance();
+            self.emit(Event::NodeRecovered {
+                node_id: node_id.to_string(),
+            });
+        }
+
+        // 记录错误状态日志，刚进入错误状态时发布事件
+        if matches!(runtime_status, NodeRuntimeStatus::Error) {
+            tracing::warn!(
+                "Node {} reported error status (active containers: {})",
+                node_id,
+                active_containers
+            );
+            if !was_error {
+                self.emit(Event::NodeError {
+                    node_id: node_id.to_string(),
+                    active_containers,
+                });
             }
+        }
 
-            true
-        } else {
-            false
+        true
+    }
+
+    /// 设置节点运行模式（节点在下次心跳时收到）
+    /// 排空或停止的节点不再分配新任务；恢复为 Active 后重新参与分配
+    /// 返回节点是否存在
+    pub fn set_node_mode(&mut self, node_id: &str, mode: NodeMode) -> bool {
+        let Some(node) = self.nodes.get_mut(node_id) else {
+            return false;
+        };
+        if node.mode == mode {
+            return true;
         }
+        node.mode = mode;
+        self.rebalance();
+        let node_id = node_id.to_string();
+        self.persist(JournalEntry::SetNodeMode {
+            node_id: node_id.clone(),
+            mode,
+        });
+        self.emit(match mode {
+            NodeMode::Active => Event::NodeResumed { node_id },
+            NodeMode::Draining => Event::NodeDrainRequested { node_id },
+            NodeMode::Stopping => Event::NodeStopRequested { node_id },
+        });
+        true
+    }
+
+    /// 记录 Running 任务首次有容器运行（只记录一次，重新开始运行时清除）
+    fn mark_task_run(&mut self, name: &str) {
+        let now = self.now();
+        let Some(entry) = self.tasks.iter_mut().find(|entry| {
+            entry.task.name == name
+                && entry.status == TaskStatus::Running
+                && entry.first_run_at.is_none()
+        }) else {
+            return;
+        };
+        entry.first_run_at = Some(now);
+        self.persist(JournalEntry::MarkTaskRun {
+            name: name.to_string(),
+        });
     }
 
-    /// 清理超时节点
-    pub fn cleanup_offline_nodes(&mut self, timeout_secs: i64) {
+    /// 设置节点并行度（None 恢复节点本地配置），按新的容量重新分配任务
+    /// 返回节点是否存在
+    pub fn set_node_parallelism(&mut self, node_id: &str, parallelism: Option<u32>) -> bool {
+        let Some(node) = self.nodes.get_mut(node_id) else {
+            return false;
+        };
+        if node.parallelism == parallelism {
+            return true;
+        }
+        node.parallelism = parallelism;
+        self.rebalance();
+        self.persist(JournalEntry::SetNodeParallelism {
+            node_id: node_id.to_string(),
+            parallelism,
+        });
+        true
+    }
+
+    /// 检查节点心跳（按 config 中的 node_timeout_secs / node_retention_secs）
+    /// 超时的在线节点标记为 Offline 并记录时间和原因，Offline 超过保留时间的节点从列表中删除
+    pub fn check_node_liveness(&mut self) {
         let now = Utc::now();
-        self.nodes.retain(|_, node| {
-            let elapsed = now.signed_duration_since(node.last_seen).num_seconds();
-            elapsed < timeout_secs
+        let timeout = chrono::Duration::seconds(self.config.node_timeout_secs as i64);
+        let retention = chrono::Duration::seconds(self.config.node_retention_secs as i64);
+
+        let timed_out: Vec<(String, OfflineReason)> = self
+            .nodes
+            .values()
+            .filter(|node| node.status == NodeStatus::Online && now - node.last_seen >= timeout)
+            .map(|node| {
+                let reason = if node.mode == NodeMode::Stopping {
+                    OfflineReason::Stopped
+                } else {
+                    OfflineReason::HeartbeatTimeout
+                };
+                (node.id.clone(), reason)
+            })
+            .collect();
+        for (node_id, reason) in &timed_out {
+            tracing::warn!("Node {} is offline ({:?})", node_id, reason);
+            self.mark_node_offline(node_id, now, *reason);
+        }
+
+        let purged: Vec<String> = self
+            .nodes
+            .values()
+            .filter(|node| node.offline_since.is_some_and(|since| now - since >= retention))
+            .map(|node| node.id.clone())
+            .collect();
+        for node_id in &purged {
+            tracing::info!("Purged offline node {}", node_id);
+            self.purge_node(node_id);
+        }
+
+        if !timed_out.is_empty() {
+            self.rebalance();
+        }
+        for (node_id, reason) in timed_out {
+            self.emit(Event::NodeTimedOut { node_id, reason });
+        }
+        for node_id in purged {
+            self.emit(Event::NodePurged { node_id });
+        }
+    }
+
+    /// 把节点标记为 Offline（不重新分配任务，由调用方调用 rebalance）
+    fn mark_node_offline(&mut self, node_id: &str, since: DateTime<Utc>, reason: OfflineReason) {
+        let Some(node) = self.nodes.get_mut(node_id) else {
+            return;
+        };
+        node.status = NodeStatus::Offline;
+        node.offline_since = Some(since);
+        node.offline_reason = Some(reason);
+        node.runtime_status = None;
+        node.active_containers = 0;
+        node.instances.clear();
+        self.persist(JournalEntry::NodeOffline {
+            node_id: node_id.to_string(),
+            since,
+            reason,
         });
     }
+
+    /// 从节点列表删除 Offline 节点（专属凭据保留，节点之后仍可用它重新注册）
+    fn purge_node(&mut self, node_id: &str) {
+        if self.nodes.remove(node_id).is_some() {
+            self.persist(JournalEntry::PurgeNode {
+                node_id: node_id.to_string(),
+            });
+        }
+    }
 }
 
 /// 注册任务请求
@@ -310,12 +1690,531 @@ pub struct CreateTaskRequest {
     pub output_redis: Option<String>,
     pub input_queue: Option<String>,
     pub output_queue: Option<String>,
+    /// 优先级（越大越先执行，默认 0）
+    #[serde(default)]
+    pub priority: i32,
+    /// 多任务并行时的节点分配权重（默认 1）
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /// 多任务并行时最多占用的容器数
+    pub max_containers: Option<u32>,
+    /// 输入队列清空后自动完成
+    pub auto_finish: Option<AutoFinishPolicy>,
+    /// 每个容器的资源需求
+    pub resources: Option<TaskResources>,
+    /// 额外的容器环境变量
+    pub env: Option<HashMap<String, String>>,
+    /// 覆盖镜像的 CMD
+    pub command: Option<Vec<String>>,
+    /// 覆盖镜像的 ENTRYPOINT
+    pub entrypoint: Option<Vec<String>>,
+    /// 节点选择器
+    pub node_selector: Option<HashMap<String, String>>,
+}
+
+/// 字段校验错误
+#[derive(Debug, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// 结构化错误响应
+#[derive(Debug, Serialize)]
+pub struct ErrorResponse {
+    /// 错误代码（invalid_request / duplicate_task / invalid_task / task_not_found / invalid_status / no_running_task）
+    pub error: &'static str,
+    pub message: String,
+    /// 不合法的字段（仅 invalid_task）
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+/// 结束任务请求（finish/cancel/fail，可选）
+#[derive(Debug, Deserialize)]
+pub struct EndTaskRequest {
+    /// 要结束的任务名，省略时为主任务（最靠前的 Running 任务）
+    pub name: Option<String>,
+}
+
+/// 更新任务请求：省略的字段保持不变，可选字段设为 null 时清除（恢复默认）
+/// 外层 None 表示省略，Some(None) 表示 null
+#[derive(Debug, Deserialize)]
+pub struct UpdateTaskRequest {
+    #[serde(default, deserialize_with = "double_option")]
+    pub image: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub images: Option<Option<HashMap<String, String>>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub input_redis: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub output_redis: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub input_queue: Option<Option<String>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub output_queue: Option<Option<String>>,
+    pub weight: Option<u32>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub max_containers: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub auto_finish: Option<Option<AutoFinishPolicy>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub resources: Option<Option<TaskResources>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub env: Option<Option<HashMap<String, String>>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub command: Option<Option<Vec<String>>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub entrypoint: Option<Option<Vec<String>>>,
+    #[serde(default, deserialize_with = "double_option")]
+    pub node_selector: Option<Option<HashMap<String, String>>>,
+}
+
+/// 区分省略的字段（None）和显式的 null（Some(None)）
+fn double_option<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+impl UpdateTaskRequest {
+    /// 将请求中给出的字段合并到现有定义上
+    pub fn apply_to(self, task: &Task) -> Task {
+        let mut task = task.clone();
+        if let Some(weight) = self.weight {
+            task.weight = weight;
+        }
+        if let Some(image) = self.image {
+            task.image = image;
+        }
+        if let Some(images) = self.images {
+            task.images = images;
+        }
+        if let Some(input_redis) = self.input_redis {
+            task.input_redis = input_redis;
+        }
+        if let Some(output_redis) = self.output_redis {
+            task.output_redis = output_redis;
+        }
+        if let Some(input_queue) = self.input_queue {
+            task.input_queue = input_queue;
+        }
+        if let Some(output_queue) = self.output_queue {
+            task.output_queue = output_queue;
+        }
+        if let Some(max_containers) = self.max_containers {
+            task.max_containers = max_containers;
+        }
+        if let Some(auto_finish) = self.auto_finish {
+            task.auto_finish = auto_finish;
+        }
+        if let Some(resources) = self.resources {
+            task.resources = resources;
+        }
+        if let Some(env) = self.env {
+            task.env = env;
+        }
+        if let Some(command) = self.command {
+            task.command = command;
+        }
+        if let Some(entrypoint) = self.entrypoint {
+            task.entrypoint = entrypoint;
+        }
+        if let Some(node_selector) = self.node_selector {
+            task.node_selector = node_selector;
+        }
+        task
+    }
+}
+
+/// 设置节点并行度请求（parallelism 为 null 或省略时恢复节点本地配置）
+#[derive(Debug, Deserialize)]
+pub struct SetParallelismRequest {
+    #[serde(default)]
+    pub parallelism: Option<u32>,
+}
+
+/// 调整任务优先级/队列位置请求
+#[derive(Debug, Deserialize)]
+pub struct RepositionTaskRequest {
+    pub priority: Option<i32>,
+    pub position: Option<usize>,
+}
+
+/// 任务详情响应
+#[derive(Debug, Serialize)]
+pub struct TaskDetailResponse {
+    #[serde(flatten)]
+    pub task: TaskWithStatus,
+    /// 当前正在执行该任务的节点
+    pub nodes: Vec<TaskNodeInfo>,
+}
+
+/// 节点详情响应
+#[derive(Debug, Serialize)]
+pub struct NodeDetailResponse {
+    #[serde(flatten)]
+    pub node: Node,
+    /// 最近一次心跳上报的各工作实例状态（按 instance_id 排序）
+    pub instances: Vec<InstanceStatus>,
+}
+
+/// 执行任务的节点摘要
+#[derive(Debug, Serialize)]
+pub struct TaskNodeInfo {
+    pub id: String,
+    pub hostname: String,
+    pub active_containers: u32,
+    pub last_seen: DateTime<Utc>,
 }
 
 /// 任务列表响应
 #[derive(Debug, Serialize)]
 pub struct TaskListResponse {
+    /// 主任务（最靠前的 Running 任务）
     pub current: Option<String>,
+    /// 所有 Running 任务
+    pub running: Vec<String>,
+    /// 按调度顺序排列
     pub pending: Vec<String>,
     pub completed: Vec<String>,
+    pub cancelled: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(name: &str, priority: i32) -> Task {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "image": "example/worker:latest",
+            "priority": priority,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn restore_skips_journal_entries_already_in_snapshot() {
+        let dir = std::env::temp_dir().join(format!("state-{}", uuid::Uuid::new_v4()));
+        let restart = || {
+            let mut state = AppStateInner::new(ServerConfig::default());
+            state.restore(StateStore::open(&dir).unwrap()).unwrap();
+            state
+        };
+
+        let mut state = restart();
+        state.add_task(task("a", 0));
+        state.add_task(task("b", 0));
+        state.next_task();
+
+        // 模拟写入快照后、清空日志前崩溃：快照已包含这些变更，日志仍然保留
+        let journal_path = dir.join("journal.jsonl");
+        let journal = std::fs::read_to_string(&journal_path).unwrap();
+        let snapshot = state.snapshot();
+        state.store.as_mut().unwrap().write_snapshot(snapshot).unwrap();
+        std::fs::write(&journal_path, journal).unwrap();
+
+        let mut state = restart();
+        let statuses: Vec<(&str, TaskStatus)> = state
+            .tasks
+            .iter()
+            .map(|entry| (entry.task.name.as_str(), entry.status))
+            .collect();
+        assert_eq!(
+            statuses,
+            [("a", TaskStatus::Completed), ("b", TaskStatus::Running)]
+        );
+
+        // 之后追加的日志序号接在快照之后，重启后正常重放
+        state.add_task(task("c", 0));
+        let state = restart();
+        assert_eq!(state.tasks.len(), 3);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn pending_names(state: &AppStateInner) -> Vec<&str> {
+        state
+            .pending_order()
+            .into_iter()
+            .map(|idx| state.tasks[idx].task.name.as_str())
+            .collect()
+    }
+
+    /// 一个 Running 任务加上指定的 Pending 任务
+    fn state_with_pending(tasks: &[(&str, i32)]) -> AppStateInner {
+        let mut state = AppStateInner::new(ServerConfig::default());
+        state.add_task(task("running", 100));
+        for &(name, priority) in tasks {
+            state.add_task(task(name, priority));
+        }
+        state
+    }
+
+    #[test]
+    fn reposition_moves_within_same_priority() {
+        let mut state = state_with_pending(&[("a", 0), ("b", 0), ("c", 0)]);
+        state.reposition_task("c", None, Some(0)).unwrap();
+        assert_eq!(pending_names(&state), ["c", "a", "b"]);
+        state.reposition_task("c", None, Some(1)).unwrap();
+        assert_eq!(pending_names(&state), ["a", "c", "b"]);
+        state.reposition_task("a", None, Some(10)).unwrap();
+        assert_eq!(pending_names(&state), ["c", "b", "a"]);
+    }
+
+    #[test]
+    fn reposition_position_is_clamped_by_priority() {
+        let mut state = state_with_pending(&[("high", 10), ("a", 0), ("b", 0), ("low", -5)]);
+
+        // 位置 0 被更高优先级的任务占据，只能排到同优先级的第一个
+        state.reposition_task("b", None, Some(0)).unwrap();
+        assert_eq!(pending_names(&state), ["high", "b", "a", "low"]);
+
+        // 超出同优先级区间时排在最后一个同优先级任务之后
+        state.reposition_task("b", None, Some(3)).unwrap();
+        assert_eq!(pending_names(&state), ["high", "a", "b", "low"]);
+
+        // 同时修改优先级时按新优先级的区间计算
+        state.reposition_task("low", Some(10), Some(0)).unwrap();
+        assert_eq!(pending_names(&state), ["low", "high", "a", "b"]);
+    }
+
+    fn node(id: &str, cpu_count: u32, labels: &[(&str, &str)]) -> Node {
+        Node {
+            id: id.to_string(),
+            hostname: id.to_string(),
+            architecture: "x86_64".to_string(),
+            cpu_count,
+            last_seen: Utc::now(),
+            status: NodeStatus::Online,
+            runtime_status: None,
+            active_containers: 0,
+            mode: NodeMode::Active,
+            parallelism: None,
+            assigned_task: None,
+            assigned_instances: None,
+            labels: labels
+                .iter()
+                .map(|&(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            offline_since: None,
+            offline_reason: None,
+            registered_by_certificate: false,
+            instances: Vec::new(),
+        }
+    }
+
+    /// max_running_tasks 个任务同时运行的状态
+    fn state_with_running(tasks: Vec<Task>, nodes: Vec<Node>) -> AppStateInner {
+        let mut state = AppStateInner::new(ServerConfig {
+            max_running_tasks: tasks.len(),
+            ..ServerConfig::default()
+        });
+        for node in nodes {
+            state.register_node(node, None);
+        }
+        for task in tasks {
+            state.add_task(task);
+        }
+        state
+    }
+
+    fn with(mut task: Task, update: impl FnOnce(&mut Task)) -> Task {
+        update(&mut task);
+        task
+    }
+
+    /// 分配给任务的节点（按 ID 排序）
+    fn nodes_of<'a>(state: &'a AppStateInner, task: &str) -> Vec<&'a str> {
+        let mut ids: Vec<&str> = state
+            .nodes
+            .values()
+            .filter(|node| node.assigned_task.as_deref() == Some(task))
+            .map(|node| node.id.as_str())
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    /// 任务在整个集群中运行的容器数
+    fn containers_of(state: &AppStateInner, task: &str) -> u32 {
+        state
+            .nodes
+            .values()
+            .filter(|node| node.assigned_task.as_deref() == Some(task))
+            .map(|node| node.assigned_instances.unwrap_or(node.capacity()))
+            .sum()
+    }
+
+    #[test]
+    fn rebalance_splits_nodes_by_weight() {
+        let state = state_with_running(
+            vec![with(task("a", 0), |t| t.weight = 3), task("b", 0)],
+            vec![node("n1", 2, &[]), node("n2", 2, &[]), node("n3", 2, &[]), node("n4", 2, &[])],
+        );
+        assert_eq!(containers_of(&state, "a"), 6);
+        assert_eq!(containers_of(&state, "b"), 2);
+    }
+
+    #[test]
+    fn rebalance_respects_max_containers() {
+        let state = state_with_running(
+            vec![with(task("a", 0), |t| t.max_containers = Some(3)), task("b", 0)],
+            vec![node("n1", 4, &[]), node("n2", 4, &[]), node("n3", 4, &[])],
+        );
+        assert_eq!(nodes_of(&state, "a"), ["n1"]);
+        assert_eq!(state.nodes["n1"].assigned_instances, Some(3));
+        assert_eq!(containers_of(&state, "a"), 3);
+        assert_eq!(nodes_of(&state, "b"), ["n2", "n3"]);
+    }
+
+    #[test]
+    fn first_run_is_recorded_only_for_running_instances() {
+        let mut state = state_with_running(vec![task("a", 0)], vec![node("n1", 2, &[])]);
+        let instance = |state| InstanceStatus {
+            instance_id: 0,
+            state,
+            task: Some("a".to_string()),
+            image: Some("example/worker:latest".to_string()),
+            container_id: None,
+            last_exit_code: None,
+            error: None,
+            restart_count: 0,
+        };
+
+        // 拉取镜像期间还不算开始执行
+        state.update_heartbeat(
+            "n1",
+            NodeRuntimeStatus::Running,
+            0,
+            vec![instance(InstanceState::Pulling)],
+        );
+        assert_eq!(state.tasks[0].first_run_at, None);
+
+        state.update_heartbeat(
+            "n1",
+            NodeRuntimeStatus::Running,
+            1,
+            vec![instance(InstanceState::Running)],
+        );
+        let first_run = state.tasks[0].first_run_at;
+        assert!(first_run.is_some());
+
+        // 只记录第一次
+        state.update_heartbeat(
+            "n1",
+            NodeRuntimeStatus::Running,
+            1,
+            vec![instance(InstanceState::Running)],
+        );
+        assert_eq!(state.tasks[0].first_run_at, first_run);
+
+        // 重新开始运行时清除
+        state.finish_current_task(None).unwrap();
+        state.requeue_task("a").unwrap();
+        assert_eq!(state.tasks[0].first_run_at, None);
+    }
+
+    #[test]
+    fn rebalance_reassigns_after_node_loss() {
+        let mut state = state_with_running(
+            vec![task("a", 0), task("b", 0)],
+            vec![node("n1", 2, &[]), node("n2", 2, &[]), node("n3", 2, &[])],
+        );
+        assert_eq!(nodes_of(&state, "a"), ["n1", "n2"]);
+        assert_eq!(nodes_of(&state, "b"), ["n3"]);
+
+        state.nodes.get_mut("n3").unwrap().last_seen = Utc::now() - chrono::Duration::hours(1);
+        state.check_node_liveness();
+
+        assert_eq!(state.nodes["n3"].assigned_task, None);
+        // n1 保留原有分配，n2 改为执行失去节点的任务
+        assert_eq!(nodes_of(&state, "a"), ["n1"]);
+        assert_eq!(nodes_of(&state, "b"), ["n2"]);
+    }
+
+    #[test]
+    fn node_offline_and_purge_survive_restart() {
+        let dir = std::env::temp_dir().join(format!("state-{}", uuid::Uuid::new_v4()));
+        let restart = || {
+            let mut state = AppStateInner::new(ServerConfig::default());
+            state.restore(StateStore::open(&dir).unwrap()).unwrap();
+            state
+        };
+
+        let mut state = restart();
+        state.register_node(node("n1", 2, &[]), None);
+        state.register_node(node("n2", 2, &[]), None);
+        state.nodes.get_mut("n1").unwrap().last_seen = Utc::now() - chrono::Duration::hours(1);
+        state.check_node_liveness();
+        let since = state.nodes["n1"].offline_since.unwrap();
+
+        // 恢复后仍为 Offline，保留离线时间和原因，不会被当作刚刚上线
+        let mut state = restart();
+        assert_eq!(state.nodes["n1"].status, NodeStatus::Offline);
+        assert_eq!(state.nodes["n1"].offline_since, Some(since));
+        assert_eq!(state.nodes["n1"].offline_reason, Some(OfflineReason::HeartbeatTimeout));
+        assert_eq!(state.nodes["n2"].status, NodeStatus::Online);
+
+        let retention = chrono::Duration::seconds(state.config.node_retention_secs as i64);
+        state.nodes.get_mut("n1").unwrap().offline_since = Some(since - retention);
+        state.check_node_liveness();
+
+        let state = restart();
+        assert!(!state.nodes.contains_key("n1"));
+        assert!(state.nodes.contains_key("n2"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rebalance_filters_nodes_by_selector() {
+        let gpu_task = with(task("gpu", 0), |t| {
+            t.node_selector = Some(HashMap::from([("gpu".to_string(), "true".to_string())]));
+        });
+        let state = state_with_running(
+            vec![gpu_task.clone(), task("cpu", 0)],
+            vec![node("c1", 2, &[]), node("c2", 2, &[]), node("g1", 2, &[("gpu", "true")])],
+        );
+        assert_eq!(nodes_of(&state, "gpu"), ["g1"]);
+        assert_eq!(nodes_of(&state, "cpu"), ["c1", "c2"]);
+
+        // 没有匹配的节点时任务不分配节点
+        let state = state_with_running(
+            vec![gpu_task, task("cpu", 0)],
+            vec![node("c1", 2, &[]), node("c2", 2, &[])],
+        );
+        assert!(nodes_of(&state, "gpu").is_empty());
+        assert_eq!(nodes_of(&state, "cpu"), ["c1", "c2"]);
+    }
+
+    #[test]
+    fn update_request_distinguishes_omitted_and_null() {
+        let existing = with(task("a", 0), |t| {
+            t.output_queue = Some("results".to_string());
+            t.input_queue = Some("jobs".to_string());
+            t.max_containers = Some(4);
+        });
+        let req: UpdateTaskRequest = serde_json::from_value(serde_json::json!({
+            "output_queue": null,
+            "max_containers": 2,
+        }))
+        .unwrap();
+        let updated = req.apply_to(&existing);
+        assert_eq!(updated.output_queue, None);
+        assert_eq!(updated.max_containers, Some(2));
+        assert_eq!(updated.input_queue, existing.input_queue);
+        assert_eq!(updated.image, existing.image);
+    }
 }
@@ -0,0 +1,74 @@
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{interval, Duration};
+use tracing::{info, warn};
+
+use crate::config::ServerConfig;
+use crate::AppState;
+
+/// 配置文件检查间隔（秒）
+const RELOAD_CHECK_INTERVAL: u64 = 10;
+
+/// 收到 SIGHUP 或配置文件修改时间变化后重新加载 computehub.toml
+/// 新配置无效时保留当前配置，下次变化时再试
+pub fn spawn_reload(state: AppState, path: PathBuf) -> anyhow::Result<()> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        let mut last_modified = modified(&path);
+        let mut interval = interval(Duration::from_secs(RELOAD_CHECK_INTERVAL));
+        loop {
+            tokio::select! {
+                _ = hangup.recv() => {
+                    info!("Received SIGHUP, reloading {}", path.display());
+                }
+                _ = interval.tick() => {
+                    let current = modified(&path);
+                    if current == last_modified {
+                        continue;
+                    }
+                    info!("{} changed, reloading", path.display());
+                }
+            }
+            last_modified = modified(&path);
+            reload(&state, &path).await;
+        }
+    });
+    Ok(())
+}
+
+/// 加载并应用新配置，记录已生效和需要重启的配置项
+async fn reload(state: &AppState, path: &Path) {
+    let config = match ServerConfig::from_file(path) {
+        Ok(config) => config,
+        Err(e) => {
+            warn!("Ignoring invalid config {}: {}", path.display(), e);
+            return;
+        }
+    };
+
+    let changes = match state.write().await.reload_config(config) {
+        Ok(changes) => changes,
+        Err(e) => {
+            warn!("Ignoring invalid config {}: {}", path.display(), e);
+            return;
+        }
+    };
+    state.read().await.config.warn_insecure_keys();
+    if changes.applied.is_empty() {
+        info!("Config reloaded, no reloadable settings changed");
+    } else {
+        info!("Config reloaded, applied: {}", changes.applied.join(", "));
+    }
+    if !changes.restart_required.is_empty() {
+        warn!(
+            "Changes to {} require a restart to take effect",
+            changes.restart_required.join(", ")
+        );
+    }
+}
+
+/// 配置文件的修改时间（文件不存在时为 None）
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
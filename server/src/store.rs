@@ -0,0 +1,194 @@
+use crate::models::{Node, NodeMode, OfflineReason, Task, TaskWithStatus};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+const SNAPSHOT_FILENAME: &str = "snapshot.json";
+const JOURNAL_FILENAME: &str = "journal.jsonl";
+
+/// 日志条数超过该值时自动压缩为新快照
+const COMPACT_THRESHOLD: usize = 1000;
+
+/// 状态快照（某一时刻的完整持久化状态）
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub tasks: Vec<TaskWithStatus>,
+    pub nodes: HashMap<String, Node>,
+    /// 节点专属凭据（node_id -> 密钥的 SHA-256）
+    pub node_credentials: HashMap<String, String>,
+    /// 已吊销的节点 ID
+    pub revoked_nodes: HashSet<String>,
+    /// 快照已包含的最后一条日志的序号（由 StateStore 写入快照时填写）
+    /// 写入快照后、清空日志前崩溃时，重放会跳过序号不大于它的日志，避免重复执行
+    pub journal_seq: u64,
+}
+
+/// 日志记录：变更发生的时间 + 变更内容
+/// 重放时使用记录的时间，保证任务时间戳与重启前一致
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalRecord {
+    /// 日志序号，在存储目录内单调递增（清空日志后继续递增）
+    pub seq: u64,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub entry: JournalEntry,
+}
+
+/// 日志条目（每次状态变更追加一行 JSON）
+/// 重放时按顺序重新执行对应的 AppStateInner 方法
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalEntry {
+    AddTask { task: Task },
+    NextTask,
+    /// name 为 None 表示主任务
+    FinishTask { name: Option<String> },
+    CancelTask { name: Option<String> },
+    FailTask { name: Option<String> },
+    DeleteTask { name: String },
+    RequeueTask { name: String },
+    RepositionTask {
+        name: String,
+        priority: Option<i32>,
+        position: Option<usize>,
+    },
+    /// task 为合并后的新定义，修订号在重放时重新计算
+    UpdateTask { name: String, task: Task },
+    /// 节点首次上报 Running 任务的容器在运行（见 TaskWithStatus::first_run_at）
+    MarkTaskRun { name: String },
+    RegisterNode {
+        node: Node,
+        /// 注册时签发的节点凭据（只保存哈希）
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        credential_hash: Option<String>,
+    },
+    RevokeNode { node_id: String },
+    SetNodeMode { node_id: String, mode: NodeMode },
+    SetNodeParallelism {
+        node_id: String,
+        parallelism: Option<u32>,
+    },
+    /// 心跳超时（或停止后退出）被标记为 Offline
+    NodeOffline {
+        node_id: String,
+        since: DateTime<Utc>,
+        reason: OfflineReason,
+    },
+    /// Offline 节点恢复心跳
+    NodeOnline { node_id: String },
+    /// Offline 超过保留时间后从节点列表删除
+    PurgeNode { node_id: String },
+}
+
+/// 持久化存储：快照 + 追加日志
+/// 目录结构：
+///   <dir>/snapshot.json   最近一次快照
+///   <dir>/journal.jsonl   快照之后的变更日志
+#[derive(Debug)]
+pub struct StateStore {
+    dir: PathBuf,
+    journal: File,
+    /// 自上次快照以来追加的日志条数
+    journal_len: usize,
+    /// 最后一条日志的序号
+    seq: u64,
+}
+
+impl StateStore {
+    /// 打开（或创建）存储目录
+    pub fn open<P: AsRef<Path>>(dir: P) -> anyhow::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+        let journal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(JOURNAL_FILENAME))?;
+        Ok(Self {
+            dir,
+            journal,
+            journal_len: 0,
+            seq: 0,
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// 读取快照和快照之后的日志
+    /// 日志末尾不完整的行（写入中途崩溃）会被跳过；已包含在快照中的日志（序号不大于 journal_seq）也会被跳过
+    pub fn load(&mut self) -> anyhow::Result<(Option<Snapshot>, Vec<JournalRecord>)> {
+        let snapshot_path = self.dir.join(SNAPSHOT_FILENAME);
+        let snapshot: Option<Snapshot> = if snapshot_path.exists() {
+            let content = std::fs::read_to_string(&snapshot_path)?;
+            Some(serde_json::from_str(&content)?)
+        } else {
+            None
+        };
+        let snapshot_seq = snapshot.as_ref().map_or(0, |snapshot| snapshot.journal_seq);
+        self.seq = snapshot_seq;
+
+        let mut entries = Vec::new();
+        let reader = BufReader::new(File::open(self.dir.join(JOURNAL_FILENAME))?);
+        for (line_no, line) in reader.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str::<JournalRecord>(&line) {
+                Ok(record) if record.seq <= snapshot_seq => {}
+                Ok(record) => {
+                    self.seq = self.seq.max(record.seq);
+                    entries.push(record);
+                }
+                Err(e) => warn!("Skipping corrupt journal line {}: {}", line_no + 1, e),
+            }
+        }
+        self.journal_len = entries.len();
+
+        Ok((snapshot, entries))
+    }
+
+    /// 追加一条日志并落盘
+    pub fn append(&mut self, at: DateTime<Utc>, entry: JournalEntry) -> anyhow::Result<()> {
+        let record = JournalRecord {
+            seq: self.seq + 1,
+            at,
+            entry,
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        self.journal.write_all(line.as_bytes())?;
+        self.journal.sync_data()?;
+        self.seq = record.seq;
+        self.journal_len += 1;
+        Ok(())
+    }
+
+    /// 日志是否已经足够长，需要压缩
+    pub fn needs_compaction(&self) -> bool {
+        self.journal_len >= COMPACT_THRESHOLD
+    }
+
+    /// 写入新快照并清空日志
+    /// 先写临时文件再 rename，保证快照文件始终完整；快照记录当前日志序号，清空日志前崩溃也不会重复重放
+    pub fn write_snapshot(&mut self, mut snapshot: Snapshot) -> anyhow::Result<()> {
+        snapshot.journal_seq = self.seq;
+        let tmp_path = self.dir.join(format!("{}.tmp", SNAPSHOT_FILENAME));
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            tmp.write_all(serde_json::to_string_pretty(&snapshot)?.as_bytes())?;
+            tmp.sync_all()?;
+        }
+        std::fs::rename(&tmp_path, self.dir.join(SNAPSHOT_FILENAME))?;
+
+        self.journal.set_len(0)?;
+        self.journal.sync_all()?;
+        self.journal_len = 0;
+        Ok(())
+    }
+}
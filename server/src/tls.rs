@@ -0,0 +1,187 @@
+use axum::{middleware::AddExtension, Extension};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::future::Future;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::{interval, Duration};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use tracing::{info, warn};
+
+/// 证书文件检查间隔（秒）
+const RELOAD_CHECK_INTERVAL: u64 = 10;
+
+/// TLS 相关文件（PEM 格式）
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    /// 服务端证书（可包含证书链）
+    pub cert: PathBuf,
+    /// 服务端私钥
+    pub key: PathBuf,
+    /// 签发 GridNode 客户端证书的 CA（设置后启用双向 TLS）
+    pub client_ca: Option<PathBuf>,
+}
+
+/// 客户端证书中的节点身份（由 NodeCertAcceptor 放入该连接上每个请求的扩展）
+#[derive(Debug, Clone, Default)]
+pub struct ClientCertificate {
+    /// 证书主题的 CN，即节点 ID（未出示证书时为 None）
+    pub node_id: Option<String>,
+}
+
+/// 加载 TLS 证书和私钥
+pub fn load(files: &TlsFiles) -> anyhow::Result<RustlsConfig> {
+    // 只启用了 ring，显式安装为默认实现（重复安装返回 Err，可以忽略）
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    Ok(RustlsConfig::from_config(Arc::new(server_config(files)?)))
+}
+
+/// 构建 rustls 服务端配置
+/// 配置了 client_ca 时校验客户端证书，但允许不出示证书的连接（管理员接口和使用密钥的节点）
+fn server_config(files: &TlsFiles) -> anyhow::Result<ServerConfig> {
+    let certs = read_certs(&files.cert)?;
+    let key = read_key(&files.key)?;
+
+    let builder = ServerConfig::builder();
+    let builder = match &files.client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for cert in read_certs(client_ca)? {
+                roots.add(cert)?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// 读取 PEM 文件中的所有证书
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file)).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// 读取 PEM 文件中的私钥
+fn read_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = std::fs::File::open(path)
+        .map_err(|e| anyhow::anyhow!("Failed to open {}: {}", path.display(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", path.display()))
+}
+
+/// 定期检查证书、私钥和客户端 CA 的修改时间，变化后重新加载（续期证书无需重启 ComputeHub）
+/// 加载失败时保留当前配置，下次检查再重试
+pub fn spawn_reload(config: RustlsConfig, files: TlsFiles) {
+    tokio::spawn(async move {
+        let mut last_modified = modified(&files);
+        let mut interval = interval(Duration::from_secs(RELOAD_CHECK_INTERVAL));
+        loop {
+            interval.tick().await;
+            let current = modified(&files);
+            if current == last_modified {
+                continue;
+            }
+            match server_config(&files) {
+                Ok(server_config) => {
+                    config.reload_from_config(Arc::new(server_config));
+                    info!("Reloaded TLS certificate from {}", files.cert.display());
+                    last_modified = current;
+                }
+                Err(e) => {
+                    // 证书和私钥可能还没有全部写完，下次检查时重试
+                    warn!("Failed to reload TLS certificate: {}", e);
+                }
+            }
+        }
+    });
+}
+
+/// 各文件的修改时间（文件不存在时为 None）
+fn modified(files: &TlsFiles) -> Vec<Option<SystemTime>> {
+    let mtime = |path: &Path| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    [Some(&files.cert), Some(&files.key), files.client_ca.as_ref()]
+        .into_iter()
+        .flatten()
+        .map(|path| mtime(path))
+        .collect()
+}
+
+/// 在 TLS 握手后读取客户端证书，把节点身份附加到该连接的请求上
+#[derive(Debug, Clone)]
+pub struct NodeCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl NodeCertAcceptor {
+    pub fn new(config: RustlsConfig) -> Self {
+        Self {
+            inner: RustlsAcceptor::new(config),
+        }
+    }
+}
+
+impl<I, S> Accept<I, S> for NodeCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientCertificate>;
+    type Future =
+        Pin<Box<dyn Future<Output = std::io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let node_id = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| certificate_node_id(cert));
+            let service = Extension(ClientCertificate { node_id }).layer(service);
+            Ok((stream, service))
+        })
+    }
+}
+
+/// 从证书主题中取出 CN 作为节点 ID（证书已由 rustls 校验过）
+fn certificate_node_id(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let node_id = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .filter(|cn| !cn.is_empty())
+        .map(str::to_string);
+    if node_id.is_none() {
+        warn!("Client certificate has no common name, ignoring it");
+    }
+    node_id
+}
@@ -0,0 +1,299 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::{sleep, Duration};
+use tracing::{debug, info, warn};
+
+use crate::config::WebhookConfig;
+use crate::events::EventRecord;
+use crate::AppState;
+
+/// 每个 webhook 等待投递的事件数上限（超出时丢弃新事件）
+const QUEUE_SIZE: usize = 256;
+/// 单次请求超时（秒）
+const REQUEST_TIMEOUT: u64 = 10;
+/// 重试间隔的初始值和上限（秒），每次失败后翻倍
+const INITIAL_BACKOFF: u64 = 1;
+const MAX_BACKOFF: u64 = 60;
+
+/// 待投递的事件
+struct Delivery {
+    /// 事件序号
+    id: u64,
+    /// 投递 ID（X-GridCore-Delivery）：本次启动的随机前缀 + 事件序号，重试时不变，重启后也不会重复
+    delivery_id: String,
+    event_type: String,
+    body: String,
+}
+
+/// 启动 webhook 投递：订阅事件总线，按各 webhook 的事件过滤条件放入其投递队列
+/// 每个 webhook 有独立的队列和投递任务，一个地址不可用不会影响其他 webhook
+pub async fn spawn_webhooks(state: AppState) -> anyhow::Result<()> {
+    let (webhooks, mut events) = {
+        let state = state.read().await;
+        (state.config.webhooks.clone(), state.events.subscribe(None).1)
+    };
+    if webhooks.is_empty() {
+        return Ok(());
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(REQUEST_TIMEOUT))
+        .build()?;
+    let queues: Vec<(WebhookConfig, mpsc::Sender<Delivery>)> = webhooks
+        .into_iter()
+        .map(|webhook| {
+            let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+            tokio::spawn(deliver_loop(client.clone(), webhook.clone(), rx));
+            (webhook, tx)
+        })
+        .collect();
+    info!("Webhooks enabled for {} endpoint(s)", queues.len());
+    // 事件序号在重启后从 1 开始，加上每次启动不同的前缀，接收方按投递 ID 去重时不会误丢事件
+    let boot_id = uuid::Uuid::new_v4().simple().to_string();
+
+    tokio::spawn(async move {
+        loop {
+            let record = match events.recv().await {
+                Ok(record) => record,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Webhook dispatcher lagged behind, {} events not delivered", skipped);
+                    continue;
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            };
+
+            let (event_type, body) = match serialize(&record) {
+                Ok(serialized) => serialized,
+                Err(e) => {
+                    warn!("Failed to serialize event {}: {}", record.id, e);
+                    continue;
+                }
+            };
+            for (webhook, queue) in &queues {
+                if !webhook.matches(&event_type) {
+                    continue;
+                }
+                let delivery = Delivery {
+                    id: record.id,
+                    delivery_id: format!("{}-{}", boot_id, record.id),
+                    event_type: event_type.clone(),
+                    body: body.clone(),
+                };
+                if queue.try_send(delivery).is_err() {
+                    warn!(
+                        "Webhook queue for {} is full, dropping event {}",
+                        webhook.url, record.id
+                    );
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+/// 事件类型和请求体（JSON）
+fn serialize(record: &EventRecord) -> anyhow::Result<(String, String)> {
+    let value = serde_json::to_value(record)?;
+    let event_type = value["type"].as_str().unwrap_or_default().to_string();
+    Ok((event_type, serde_json::to_string(&value)?))
+}
+
+/// 按顺序投递一个 webhook 的事件
+async fn deliver_loop(
+    client: reqwest::Client,
+    webhook: WebhookConfig,
+    mut queue: mpsc::Receiver<Delivery>,
+) {
+    while let Some(delivery) = queue.recv().await {
+        deliver(&client, &webhook, &delivery).await;
+    }
+}
+
+/// 投递一个事件，失败时按指数退避重试，最多 max_attempts 次
+async fn deliver(client: &reqwest::Client, webhook: &WebhookConfig, delivery: &Delivery) {
+    let signature = webhook
+        .secret
+        .as_deref()
+        .map(|secret| sign(secret, &delivery.body));
+    let mut backoff = INITIAL_BACKOFF;
+
+    for attempt in 1..=webhook.max_attempts.max(1) {
+        let mut request = client
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-GridCore-Event", &delivery.event_type)
+            .header("X-GridCore-Delivery", &delivery.delivery_id)
+            .body(delivery.body.clone());
+        if let Some(signature) = &signature {
+            request = request.header("X-GridCore-Signature", signature);
+        }
+
+        let retryable = match request.send().await {
+            Ok(resp) if resp.status().is_success() => {
+                debug!("Delivered event {} to {}", delivery.id, webhook.url);
+                return;
+            }
+            Ok(resp) => {
+                let status = resp.status();
+                warn!(
+                    "Webhook {} returned {} for event {} (attempt {}/{})",
+                    webhook.url, status, delivery.id, attempt, webhook.max_attempts
+                );
+                // 4xx 说明请求本身被拒绝，重试也不会成功（408/429 除外）
+                status.is_server_error()
+                    || status == reqwest::StatusCode::REQUEST_TIMEOUT
+                    || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            Err(e) => {
+                warn!(
+                    "Webhook {} failed for event {} (attempt {}/{}): {}",
+                    webhook.url, delivery.id, attempt, webhook.max_attempts, e
+                );
+                true
+            }
+        };
+
+        if !retryable || attempt >= webhook.max_attempts {
+            break;
+        }
+        sleep(Duration::from_secs(backoff)).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    warn!("Giving up delivering event {} to {}", delivery.id, webhook.url);
+}
+
+/// 请求体的 HMAC-SHA256 签名：sha256=<十六进制>
+fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::Event;
+    use crate::models::TaskStatus;
+    use axum::{Router, extract::State, http::HeaderMap, http::StatusCode, routing::post};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    /// 接收端收到的请求
+    struct Received {
+        event_type: Option<String>,
+        delivery_id: Option<String>,
+        signature: Option<String>,
+        body: String,
+    }
+
+    /// 本地接收端：记录收到的请求，按顺序返回预设的状态码（用完后返回 200）
+    #[derive(Clone, Default)]
+    struct Receiver {
+        requests: Arc<Mutex<Vec<Received>>>,
+        responses: Arc<Mutex<VecDeque<StatusCode>>>,
+    }
+
+    async fn receive(
+        State(receiver): State<Receiver>,
+        headers: HeaderMap,
+        body: String,
+    ) -> StatusCode {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+        };
+        receiver.requests.lock().unwrap().push(Received {
+            event_type: header("X-GridCore-Event"),
+            delivery_id: header("X-GridCore-Delivery"),
+            signature: header("X-GridCore-Signature"),
+            body,
+        });
+        receiver
+            .responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or(StatusCode::OK)
+    }
+
+    /// 启动接收端，返回其地址
+    async fn start_receiver(receiver: Receiver, responses: &[StatusCode]) -> String {
+        receiver.responses.lock().unwrap().extend(responses);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new()
+            .route("/hook", post(receive))
+            .with_state(receiver);
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        format!("http://{}/hook", addr)
+    }
+
+    fn webhook(url: String) -> WebhookConfig {
+        WebhookConfig {
+            url,
+            events: Vec::new(),
+            secret: Some("s3cret".to_string()),
+            max_attempts: 3,
+        }
+    }
+
+    /// 由真实事件序列化得到的投递
+    fn delivery() -> Delivery {
+        let record = EventRecord {
+            id: 7,
+            at: chrono::Utc::now(),
+            event: Event::TaskFinished {
+                task: "render".to_string(),
+                status: TaskStatus::Completed,
+            },
+        };
+        let (event_type, body) = serialize(&record).unwrap();
+        Delivery {
+            id: record.id,
+            delivery_id: "boot-7".to_string(),
+            event_type,
+            body,
+        }
+    }
+
+    #[tokio::test]
+    async fn deliver_signs_body_and_retries_after_server_error() {
+        let receiver = Receiver::default();
+        let url = start_receiver(receiver.clone(), &[StatusCode::INTERNAL_SERVER_ERROR]).await;
+        let delivery = delivery();
+
+        deliver(&reqwest::Client::new(), &webhook(url), &delivery).await;
+
+        let requests = receiver.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(delivery.body.as_bytes());
+        let expected = format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        for received in requests.iter() {
+            assert_eq!(received.body, delivery.body);
+            assert_eq!(received.event_type.as_deref(), Some("task_finished"));
+            assert_eq!(received.delivery_id.as_deref(), Some("boot-7"));
+            assert_eq!(received.signature.as_deref(), Some(expected.as_str()));
+        }
+
+        let body: serde_json::Value = serde_json::from_str(&requests[0].body).unwrap();
+        assert_eq!(body["id"], 7);
+        assert_eq!(body["type"], "task_finished");
+        assert_eq!(body["task"], "render");
+        assert_eq!(body["status"], "Completed");
+    }
+
+    #[tokio::test]
+    async fn deliver_gives_up_on_client_error() {
+        let receiver = Receiver::default();
+        let url = start_receiver(receiver.clone(), &[StatusCode::BAD_REQUEST]).await;
+
+        deliver(&reqwest::Client::new(), &webhook(url), &delivery()).await;
+
+        assert_eq!(receiver.requests.lock().unwrap().len(), 1);
+    }
+}